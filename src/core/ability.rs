@@ -0,0 +1,315 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::core::{
+    attack_fn::{attack_ring, attack_teleport, AttackState},
+    bloon::{Bloon, BloonID},
+    buff::{give_buff, BuffDef, BuffScope, TowerBuff},
+    command::PlayerCommand,
+    game::{GameState, RoundStartEvent},
+    map::Map,
+    tower::{AttackStats, AttackTarget, Tower, TowerId},
+    tower_def::{ProjectileStats, TowerDefinitions},
+    upgradable::Upgradable,
+};
+
+/*
+    Ability data
+*/
+
+/// What the player has to pick when activating an ability
+#[derive(Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum AbilityTargeting {
+    #[default]
+    None,
+    Road,
+    Bloon,
+    Tower,
+}
+
+/// What an ability does when its cooldown comes up at the start of a round
+#[derive(Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum AbilityRoundStart {
+    #[default]
+    Keep, // the cooldown carries on where it was
+    Ready, // every round starts with the ability charged
+}
+
+/// An activated ability of a tower prototype
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AbilityDef {
+    pub name: String,
+    pub effect: String, // name in the ability effect registry (see `get_ability_fn`)
+    pub cooldown: i32, // ticks (of active rounds)
+    #[serde(default)]
+    pub initial_cooldown: Option<i32>, // cooldown right after the ability is gained; the full cooldown if None
+    #[serde(default)]
+    pub targeting: AbilityTargeting,
+    #[serde(default)]
+    pub round_start: AbilityRoundStart,
+    #[serde(default)]
+    pub resets_on_upgrade: bool, // upgrading the tower puts it back to `initial_cooldown` instead of keeping the cooldown
+    #[serde(default)]
+    pub buff: Option<BuffDef>, // for buffing effects
+    #[serde(default)]
+    pub projectile: Option<ProjectileStats>, // for damaging effects
+}
+
+/*
+    Ability effects
+*/
+
+/// The target an ability was used on, resolved to entities and positions
+#[derive(Clone)]
+pub enum AbilityTarget {
+    None,
+    Point(Vec2), // a spot on the road, or where the targeted bloon is
+    Tower(Entity),
+}
+
+/// Everything an ability effect gets to work with
+pub struct AbilityContext<'a> {
+    pub tower: Entity,
+    pub id: TowerId,
+    pub pos: Vec3,
+    pub target: AbilityTarget,
+    pub def: &'a AbilityDef,
+}
+
+pub type AbilityFn = fn(&mut Commands, &AbilityContext);
+
+/// Every ability effect, by the name tower data refers to it with
+const ABILITY_FNS: &[(&str, AbilityFn)] = &[
+    ("self_buff", ability_self_buff),
+    ("tower_buff", ability_tower_buff),
+    ("storm", ability_storm),
+    ("strike", ability_strike),
+];
+
+pub fn get_ability_fn(name: &str)->Option<(&'static str, AbilityFn)> {
+    return ABILITY_FNS.iter().find(|(n, _)| *n == name).copied();
+}
+
+/// The ability's buff on the tower itself (rapid shot, fan club)
+fn ability_self_buff(cmd: &mut Commands, ctx: &AbilityContext) {
+    let Some(buff) = &ctx.def.buff else { return; };
+    give_buff(cmd, ctx.tower, TowerBuff { source: Some(ctx.id), kind: buff.kind, scope: BuffScope::Single, stacking: buff.stacking, duration: buff.duration, effect: buff.effect.clone() });
+}
+
+/// The ability's buff on the targeted tower (overclock)
+fn ability_tower_buff(cmd: &mut Commands, ctx: &AbilityContext) {
+    let (Some(buff), AbilityTarget::Tower(target)) = (&ctx.def.buff, &ctx.target) else { return; };
+    give_buff(cmd, *target, TowerBuff { source: Some(ctx.id), kind: buff.kind, scope: BuffScope::Single, stacking: buff.stacking, duration: buff.duration, effect: buff.effect.clone() });
+}
+
+/// A ring of the ability's projectiles around the tower
+fn ability_storm(cmd: &mut Commands, ctx: &AbilityContext) {
+    let Some(projectile) = &ctx.def.projectile else { return; };
+    attack_ring(cmd, &AttackTarget::None, &AttackStats::from_projectile(projectile), &mut AttackState::None, ctx.pos);
+}
+
+/// The ability's projectile appears at the target (airstrike)
+fn ability_strike(cmd: &mut Commands, ctx: &AbilityContext) {
+    let (Some(projectile), AbilityTarget::Point(pos)) = (&ctx.def.projectile, &ctx.target) else { return; };
+    attack_teleport(cmd, &AttackTarget::Point(*pos), &AttackStats::from_projectile(projectile), &mut AttackState::None, ctx.pos);
+}
+
+/*
+    Abilities on towers
+*/
+
+/// An ability a tower has right now
+pub struct Ability {
+    pub def: AbilityDef,
+    pub effect: AbilityFn,
+    pub cooldown_left: i32, // only counts down during rounds
+}
+
+impl Ability {
+    fn new(def: &AbilityDef)->Self {
+        let (_, effect) = get_ability_fn(&def.effect).expect("ability effects are validated on load");
+        return Ability { def: def.clone(), effect, cooldown_left: def.initial_cooldown.unwrap_or(def.cooldown) };
+    }
+    pub fn is_ready(&self)->bool {
+        return self.cooldown_left <= 0;
+    }
+}
+
+/// The activated abilities of a tower, rebuilt from its prototype whenever its upgrades change
+#[derive(Component, Default)]
+pub struct Abilities {
+    built_for: Option<Upgradable>,
+    pub abilities: Vec<Ability>,
+}
+
+impl Abilities {
+    /// Build the abilities of a tower with `upgrades`. Abilities it already had (by name) keep their cooldown unless they reset on upgrade.
+    fn rebuild(&mut self, defs: &TowerDefinitions, tower: &Tower) {
+        let new_defs = defs.get(&tower.get_tower_type()).map_or(vec![], |def| def.resolve(tower.get_upgrades()).abilities);
+        let abilities = new_defs.iter().map(|def| {
+            let mut ability = Ability::new(def);
+            if let Some(old) = self.abilities.iter().find(|a| a.def.name == def.name) {
+                if !def.resets_on_upgrade { ability.cooldown_left = old.cooldown_left; }
+            }
+            ability
+        }).collect();
+        self.abilities = abilities;
+        self.built_for = Some(tower.get_upgrades());
+    }
+    pub fn to_save(&self)->Vec<(String, i32)> {
+        return self.abilities.iter().map(|a| (a.def.name.clone(), a.cooldown_left)).collect();
+    }
+    pub fn from_save(defs: &TowerDefinitions, tower: &Tower, save: &[(String, i32)])->Self {
+        let mut abilities = Abilities::default();
+        abilities.rebuild(defs, tower);
+        for ability in &mut abilities.abilities {
+            if let Some((_, cooldown_left)) = save.iter().find(|(name, _)| *name == ability.def.name) {
+                ability.cooldown_left = *cooldown_left;
+            }
+        }
+        return abilities;
+    }
+}
+
+/*
+    Systems
+*/
+
+/// Rebuild the abilities of towers that were placed, upgraded or levelled up
+pub fn sync_abilities(defs: Res<TowerDefinitions>, mut towers: Query<(&Tower, &mut Abilities)>) {
+    for (tower, mut abilities) in &mut towers {
+        if abilities.built_for == Some(tower.get_upgrades()) { continue; }
+        abilities.rebuild(&defs, tower);
+    }
+}
+
+/// Ability cooldowns only count down while a round is on
+pub fn tick_abilities(game: Res<GameState>, mut towers: Query<&mut Abilities>) {
+    if !game.round_active { return; }
+    for mut abilities in &mut towers {
+        for ability in &mut abilities.abilities {
+            if ability.cooldown_left > 0 { ability.cooldown_left -= 1; }
+        }
+    }
+}
+
+pub fn round_start_abilities(mut round_start_er: EventReader<RoundStartEvent>, mut towers: Query<&mut Abilities>) {
+    for _ in round_start_er.read() {
+        for mut abilities in &mut towers {
+            for ability in &mut abilities.abilities {
+                if ability.def.round_start == AbilityRoundStart::Ready { ability.cooldown_left = 0; }
+            }
+        }
+    }
+}
+
+/// Activate abilities. The target has to match what the ability asks for, and the ability has to be charged.
+pub fn apply_ability_commands(mut cmd: Commands, mut commands: EventReader<PlayerCommand>, map: Res<Map>, mut towers: Query<(Entity, &TowerId, &Transform, &mut Abilities)>, bloons: Query<(&Bloon, &Transform)>) {
+    for command in commands.read() {
+        let PlayerCommand::UseAbility { tower, ability, target } = command else { continue; };
+        // towers other than the user can be targeted, so look them up before borrowing the user mutably
+        let target_tower = match target {
+            AbilityTargetCommand::Tower(id) => towers.iter().find(|(_, tid, _, _)| **tid == *id).map(|(e, _, _, _)| e),
+            _ => None,
+        };
+        let Some((e, id, pos, mut abilities)) = towers.iter_mut().find(|(_, id, _, _)| **id == *tower) else { continue; };
+        let Some(a) = abilities.abilities.iter_mut().find(|a| a.def.name == *ability) else { continue; };
+        if !a.is_ready() { continue; }
+        let resolved = match (a.def.targeting, target) {
+            (AbilityTargeting::None, _) => AbilityTarget::None,
+            (AbilityTargeting::Road, AbilityTargetCommand::Road(pos)) if !map.path.is_empty() => AbilityTarget::Point(map.closest_pos(*pos)),
+            (AbilityTargeting::Bloon, AbilityTargetCommand::Bloon(bid)) => {
+                let Some((_, bpos)) = bloons.iter().find(|(b, _)| b.bid == *bid) else { continue; };
+                AbilityTarget::Point(bpos.translation.truncate())
+            },
+            (AbilityTargeting::Tower, AbilityTargetCommand::Tower(_)) => {
+                let Some(entity) = target_tower else { continue; };
+                AbilityTarget::Tower(entity)
+            },
+            _ => continue,
+        };
+        (a.effect)(&mut cmd, &AbilityContext { tower: e, id: *id, pos: pos.translation, target: resolved, def: &a.def });
+        a.cooldown_left = a.def.cooldown;
+    }
+}
+
+/// An ability target as the player picked it. Bloons and towers are referred to by their stable ids, so that replays work.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum AbilityTargetCommand {
+    None,
+    Road(Vec2),
+    Bloon(BloonID),
+    Tower(TowerId),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::system::RunSystemOnce;
+    use crate::core::{tower::TowerType, tower_def::TowerDef};
+
+    const ABILITIES: &str = r#"(
+        tower_type: DartMonkey,
+        cost: 100,
+        targeting_modes: [FirstBloon],
+        attacks: [],
+        paths: (
+            [(name: "Keep", cost: 100, added_abilities: [(name: "Keep", effect: "self_buff", cooldown: 100, initial_cooldown: Some(50))])],
+            [(name: "Reset", cost: 100, added_abilities: [(name: "Reset", effect: "self_buff", cooldown: 100, initial_cooldown: Some(50), resets_on_upgrade: true)])],
+            [(name: "Ready", cost: 100, added_abilities: [(name: "Ready", effect: "self_buff", cooldown: 100, round_start: Ready)])],
+        ),
+    )"#;
+
+    fn defs()->TowerDefinitions {
+        let def: TowerDef = ron::from_str(ABILITIES).unwrap();
+        return TowerDefinitions([(TowerType::DartMonkey, def)].into_iter().collect());
+    }
+
+    fn cooldown(abilities: &Abilities, name: &str)->i32 {
+        return abilities.abilities.iter().find(|a| a.def.name == name).unwrap().cooldown_left;
+    }
+
+    /// Abilities of a tower with `upgrades`, every cooldown at 10
+    fn abilities_with(defs: &TowerDefinitions, upgrades: Upgradable)->Abilities {
+        let mut abilities = Abilities::default();
+        abilities.rebuild(defs, &Tower::from(defs, TowerType::DartMonkey, upgrades));
+        for ability in &mut abilities.abilities { ability.cooldown_left = 10; }
+        return abilities;
+    }
+
+    #[test]
+    fn cooldown_through_upgrade() {
+        let defs = defs();
+        let mut abilities = abilities_with(&defs, Upgradable::Crosspath520(1, 1, 0));
+        abilities.rebuild(&defs, &Tower::from(&defs, TowerType::DartMonkey, Upgradable::Crosspath520(1, 1, 1)));
+        assert_eq!(cooldown(&abilities, "Keep"), 10);
+        assert_eq!(cooldown(&abilities, "Reset"), 50);
+        assert_eq!(cooldown(&abilities, "Ready"), 100);
+    }
+
+    #[test]
+    fn cooldowns_only_tick_during_rounds() {
+        let defs = defs();
+        let mut world = World::new();
+        world.insert_resource(GameState::default());
+        let e = world.spawn(abilities_with(&defs, Upgradable::Crosspath520(1, 0, 0))).id();
+        world.run_system_once(tick_abilities).unwrap();
+        assert_eq!(cooldown(world.get::<Abilities>(e).unwrap(), "Keep"), 10);
+        world.resource_mut::<GameState>().round_active = true;
+        world.run_system_once(tick_abilities).unwrap();
+        assert_eq!(cooldown(world.get::<Abilities>(e).unwrap(), "Keep"), 9);
+    }
+
+    #[test]
+    fn round_start_readies_only_ready_abilities() {
+        let defs = defs();
+        let mut world = World::new();
+        world.init_resource::<Events<RoundStartEvent>>();
+        let e = world.spawn(abilities_with(&defs, Upgradable::Crosspath520(1, 0, 1))).id();
+        world.send_event(RoundStartEvent { round: 1 });
+        world.run_system_once(round_start_abilities).unwrap();
+        let abilities = world.get::<Abilities>(e).unwrap();
+        assert_eq!(cooldown(abilities, "Keep"), 10);
+        assert_eq!(cooldown(abilities, "Ready"), 0);
+    }
+}
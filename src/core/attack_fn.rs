@@ -1,7 +1,266 @@
+use std::f32::consts::TAU;
+
 use bevy::{math::vec2, prelude::*};
 
-use crate::core::{movement::MoveSimple, tower::TowerEffect};
+use serde::{Deserialize, Serialize};
+
+use crate::core::{buff::{give_buff, BuffScope, TowerBuff}, hit_set::HitSet, hitbox::HitboxSimple, movement::{Bounce, MoveSimple, MoveWaypoint, SeekAfterHitMove, SteeringMove, TargetMode}, projectile::{get_collectible_sprite, get_projectile_sprite, BlowbackDealer, Collectible, DamageDealer, EffectDealer, LifetimeRound, LifetimeTick}, tower::{AttackStats, AttackTarget}};
+
+/// Spawns the projectiles of one shot, with the attack's stats (buffs included) and its own state
+pub type AttackFn = fn(&mut Commands, &AttackTarget, &AttackStats, &mut AttackState, Vec3);
+
+/// Whatever an attack function needs to remember between shots. Tower data sets the initial state.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub enum AttackState {
+    #[default]
+    None,
+    Counter(u32), // shots fired so far (alternating shots)
+    Phase(u32), // which step of a cycle the attack is on
+    Burst { queued: u32, interval: i32 }, // shots of the current burst still to fire, `interval` ticks apart instead of the cooldown
+}
+
+impl AttackState {
+    /// Ticks until the next shot if the state wants something other than the cooldown
+    pub fn next_delay(&self)->Option<i32> {
+        return match self {
+            AttackState::Burst { queued, interval } if *queued > 0 => Some(*interval),
+            _ => None,
+        };
+    }
+}
+
+/// Every attack function, by the name tower data refers to it with
+const ATTACK_FNS: &[(&str, AttackFn)] = &[
+    ("single", attack_single),
+    ("alternating", attack_alternating),
+    ("burst", attack_burst),
+    ("ring", attack_ring),
+    ("teleport", attack_teleport),
+    ("buff", attack_buff),
+    ("seeking", attack_seeking),
+    ("spikes", attack_spikes),
+    ("bananas", attack_bananas),
+];
+
+pub fn get_attack_fn(name: &str)->Option<(&'static str, AttackFn)> {
+    return ATTACK_FNS.iter().find(|(n, _)| *n == name).copied();
+}
+
+/*
+    Attack functions
+*/
+
+/// Straight flying projectiles towards the target; with multishot, fanned out evenly over `spread`
+pub fn attack_single(cmd: &mut Commands, target: &AttackTarget, stats: &AttackStats, _state: &mut AttackState, tower_pos: Vec3) {
+    spawn_fan(cmd, target.angle_from(tower_pos.truncate()), stats, tower_pos);
+}
+
+/// Like `attack_single`, but every other shot comes from the left or right side of the tower (MAD)
+pub fn attack_alternating(cmd: &mut Commands, target: &AttackTarget, stats: &AttackStats, state: &mut AttackState, tower_pos: Vec3) {
+    let angle = target.angle_from(tower_pos.truncate());
+    let shots = if let AttackState::Counter(shots) = state { *shots } else { 0 };
+    let side = if shots % 2 == 0 { 1. } else { -1. };
+    let offset = Vec2::from_angle(angle).perp() * side * 10.;
+    spawn_fan(cmd, angle, stats, tower_pos + offset.extend(0.));
+    *state = AttackState::Counter(shots.wrapping_add(1));
+}
+
+/// Fires the multishot projectiles one after another instead of all at once (Desperado)
+pub fn attack_burst(cmd: &mut Commands, target: &AttackTarget, stats: &AttackStats, state: &mut AttackState, tower_pos: Vec3) {
+    let single = AttackStats { multishot: 1, ..stats.clone() };
+    spawn_fan(cmd, target.angle_from(tower_pos.truncate()), &single, tower_pos);
+    if let AttackState::Burst { queued, .. } = state {
+        // first shot of a burst queues the rest
+        *queued = if *queued == 0 { stats.multishot.max(1) - 1 } else { *queued - 1 };
+    }
+}
+
+/// A full circle of projectiles that needs no target; each shot is turned a bit further than the last (ace)
+pub fn attack_ring(cmd: &mut Commands, _target: &AttackTarget, stats: &AttackStats, state: &mut AttackState, tower_pos: Vec3) {
+    let phase = if let AttackState::Phase(phase) = state { *phase } else { 0 };
+    let n = stats.multishot.max(1);
+    let ring = AttackStats { spread: TAU, ..stats.clone() };
+    spawn_fan(cmd, phase as f32 * TAU / n as f32 / 2., &ring, tower_pos);
+    *state = AttackState::Phase((phase + 1) % 2);
+}
+
+/// The projectile appears right at the target and stays there for its lifetime (mortar blast, instahit)
+pub fn attack_teleport(cmd: &mut Commands, target: &AttackTarget, stats: &AttackStats, _state: &mut AttackState, _tower_pos: Vec3) {
+    let pos = match target {
+        AttackTarget::Bloon { pos, .. } | AttackTarget::Point(pos) | AttackTarget::Road(pos) => *pos,
+        _ => return,
+    };
+    let mut e = cmd.spawn((
+        DamageDealer { damage: stats.damage, pierce: stats.pierce, hit_bloons: HitSet::default(), cannot_pop_modifiers: stats.cannot_pop, cannot_target_modifiers: 0, seq: 0 },
+        Transform::from_xyz(pos.x, pos.y, 1.),
+        HitboxSimple { radius: stats.radius },
+        LifetimeTick { lifetime: stats.lifetime.max(1) },
+        get_projectile_sprite(),
+    ));
+    insert_on_hit(&mut e, stats);
+}
+
+/// Like `attack_single`, but the projectiles steer onto the target bloon (Quincy's arrows; with `seek`, they go for another bloon after every hit)
+pub fn attack_seeking(cmd: &mut Commands, target: &AttackTarget, stats: &AttackStats, _state: &mut AttackState, tower_pos: Vec3) {
+    let angle = target.angle_from(tower_pos.truncate());
+    let (waypoint, target_mode) = match target {
+        AttackTarget::Bloon { entity, pos, .. } => (*pos, TargetMode::Entity(*entity)),
+        _ => (tower_pos.truncate() + Vec2::from_angle(angle) * stats.range, TargetMode::Waypoint),
+    };
+    for e in spawn_fan(cmd, angle, stats, tower_pos) {
+        cmd.entity(e).insert(SteeringMove { waypoint, steer_str: 0.15, target: target_mode.clone() });
+    }
+}
+
+/// A pile of spikes that flies to the targeted spot on the road and stays there until its pierce or lifetime runs out (spike factory)
+pub fn attack_spikes(cmd: &mut Commands, target: &AttackTarget, stats: &AttackStats, _state: &mut AttackState, tower_pos: Vec3) {
+    let AttackTarget::Road(waypoint) = target else { return; };
+    let mut e = cmd.spawn((
+        MoveWaypoint { waypoint: *waypoint, speed: stats.speed },
+        DamageDealer { damage: stats.damage, pierce: stats.pierce, hit_bloons: HitSet::default(), cannot_pop_modifiers: stats.cannot_pop, cannot_target_modifiers: 0, seq: 0 },
+        Transform::from_xyz(tower_pos.x, tower_pos.y, 1.),
+        HitboxSimple { radius: stats.radius },
+        LifetimeTick { lifetime: stats.lifetime },
+        get_projectile_sprite(),
+    ));
+    if let Some(lifetime_rounds) = stats.lifetime_rounds { e.insert(LifetimeRound { lifetime_rounds }); }
+    insert_on_hit(&mut e, stats);
+}
+
+/// A banana worth `stats.cash` that lands somewhere around the tower, each one a bit further around than the last (banana farm)
+pub fn attack_bananas(cmd: &mut Commands, _target: &AttackTarget, stats: &AttackStats, state: &mut AttackState, tower_pos: Vec3) {
+    let dropped = if let AttackState::Counter(dropped) = state { *dropped } else { 0 };
+    let angle = dropped as f32 * 2.399963; // golden angle, so that bananas don't land on each other
+    let dist = stats.range * (0.5 + 0.5 * ((dropped % 5) as f32 / 4.));
+    cmd.spawn((
+        Collectible { cash: stats.cash },
+        MoveWaypoint { waypoint: tower_pos.truncate() + Vec2::from_angle(angle) * dist, speed: stats.speed },
+        Transform::from_xyz(tower_pos.x, tower_pos.y, 1.),
+        get_collectible_sprite(),
+    ));
+    *state = AttackState::Counter(dropped.wrapping_add(1));
+}
+
+/// Give the attack's buff to the targeted tower(s) (alch, pulsing area buffs)
+pub fn attack_buff(cmd: &mut Commands, target: &AttackTarget, stats: &AttackStats, _state: &mut AttackState, _tower_pos: Vec3) {
+    let Some(def) = &stats.buff else { return; };
+    let (targets, source, scope) = match target {
+        AttackTarget::Tower { entity, source, .. } => (std::slice::from_ref(entity), *source, BuffScope::Single),
+        AttackTarget::Towers { entities, source } => (entities.as_slice(), *source, BuffScope::Area),
+        _ => return,
+    };
+    for e in targets {
+        give_buff(cmd, *e, TowerBuff { source: Some(source), kind: def.kind, scope, stacking: def.stacking, duration: def.duration, effect: def.effect.clone() });
+    }
+}
+
+/// What the projectile does to the bloons it hits, besides damage
+fn insert_on_hit(e: &mut EntityCommands, stats: &AttackStats) {
+    if stats.blowback > 0. { e.insert(BlowbackDealer { distance: stats.blowback }); }
+    if let Some(status) = stats.status { e.insert(EffectDealer { effects: status.effects() }); }
+}
+
+/// Spawn `stats.multishot` straight flying projectiles fanned out over `stats.spread` around `angle`
+fn spawn_fan(cmd: &mut Commands, angle: f32, stats: &AttackStats, tower_pos: Vec3)->Vec<Entity> {
+    let n = stats.multishot.max(1);
+    let mut spawned = Vec::with_capacity(n as usize);
+    for i in 0..n {
+        let offset = if n == 1 {
+            0.
+        } else if stats.spread >= TAU {
+            i as f32 * TAU / n as f32 // full circle; don't put two projectiles on top of each other
+        } else {
+            stats.spread * (i as f32 / (n - 1) as f32 - 0.5)
+        };
+        let a = angle + offset;
+        let mut e = cmd.spawn((
+            MoveSimple { velocity: vec2(a.cos(), a.sin()) * stats.speed, bounce: stats.bounce.map_or(0, |b| b.count), collide_height: stats.height },
+            DamageDealer { damage: stats.damage, pierce: stats.pierce, hit_bloons: HitSet::default(), cannot_pop_modifiers: stats.cannot_pop, cannot_target_modifiers: 0, seq: 0 },
+            Transform::from_xyz(tower_pos.x, tower_pos.y, 1.),
+            HitboxSimple { radius: stats.radius },
+            LifetimeTick { lifetime: stats.lifetime },
+            get_projectile_sprite(),
+        ));
+        if let Some(b) = stats.bounce { e.insert(Bounce { clear_hits: b.clear_hits }); }
+        if let Some(s) = stats.seek { e.insert(SeekAfterHitMove { last_hit: None, range: s.range, ricochet: s.ricochet, clear_hits: s.clear_hits }); }
+        insert_on_hit(&mut e, stats);
+        spawned.push(e.id());
+    }
+    return spawned;
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use crate::core::{bloon::{Bloon, BloonTier}, game::{GameState, SimTick}, map::Map, movement::MoveAlongRoad, tower::{tower_attacks, update_effective_stats, Tower, TowerId, TowerType}, tower_def::{ProjectileStats, TowerDefinitions}, upgradable::Upgradable};
+
+    use super::*;
+
+    fn stats(multishot: u32)->AttackStats {
+        let projectile: ProjectileStats = ron::from_str("(damage: 1, pierce: 1, speed: 5.0, radius: 5.0, lifetime: 20, damage_type: Normal)").unwrap();
+        return AttackStats { multishot, ..AttackStats::from_projectile(&projectile) };
+    }
+
+    /// Fire `attack` once, to the right of the origin, and return where the projectiles it spawned start
+    fn fire(world: &mut World, attack: AttackFn, stats: &AttackStats, state: &mut AttackState)->Vec<Vec2> {
+        let before: Vec<Entity> = world.query_filtered::<Entity, With<MoveSimple>>().iter(world).collect();
+        let (stats, mut s) = (stats.clone(), std::mem::take(state));
+        s = world.run_system_once(move |mut cmd: Commands| {
+            attack(&mut cmd, &AttackTarget::Angle(0.), &stats, &mut s, Vec3::ZERO);
+            return s.clone();
+        }).unwrap();
+        *state = s;
+        return world.query::<(Entity, &Transform)>().iter(world)
+            .filter(|(e, _)| !before.contains(e))
+            .map(|(_, pos)| pos.translation.truncate())
+            .collect();
+    }
+
+    #[test]
+    fn alternating_takes_turns() {
+        let mut world = World::new();
+        let mut state = AttackState::Counter(0);
+        let sides: Vec<Vec<Vec2>> = (0..3).map(|_| fire(&mut world, attack_alternating, &stats(1), &mut state)).collect();
+        assert_eq!(sides, vec![vec![vec2(0., 10.)], vec![vec2(0., -10.)], vec![vec2(0., 10.)]]);
+        assert!(matches!(state, AttackState::Counter(3)));
+    }
+
+    #[test]
+    fn burst_fires_one_at_a_time() {
+        let mut world = World::new();
+        let mut state = AttackState::Burst { queued: 0, interval: 4 };
+        let mut delays = vec![];
+        for _ in 0..3 {
+            assert_eq!(fire(&mut world, attack_burst, &stats(3), &mut state).len(), 1);
+            delays.push(state.next_delay());
+        }
+        // the last shot of a burst waits for the cooldown
+        assert_eq!(delays, vec![Some(4), Some(4), None]);
+    }
+
+    #[test]
+    fn burst_timing_on_a_tower() {
+        let defs = TowerDefinitions::load_embedded();
+        let mut world = World::new();
+        world.insert_resource(Map::get_map(1));
+        world.insert_resource(GameState::default());
+        world.insert_resource(SimTick(0));
+        let tower = Tower::from(&defs, TowerType::DartMonkey, Upgradable::Crosspath520(0, 3, 0));
+        let cooldown = defs[&TowerType::DartMonkey].resolve(Upgradable::Crosspath520(0, 3, 0)).attacks[0].attack_rate as usize;
+        world.spawn((tower, TowerId(0), Transform::from_xyz(-150., 0., 1.)));
+        let re = MoveAlongRoad { target_node: 1, road_pos: 0., waypoint: Vec2::ZERO, velocity: 0., reversed: false };
+        world.spawn((Bloon::with(BloonTier::Red, 0), re, Transform::from_xyz(-150., 50., 1.)));
 
-pub fn attack_dart000(cmd: &mut Commands, angle: f32, entity: Entity, cur_effects: &mut Vec<TowerEffect>, tower_pos: Vec3) {
-    cmd.spawn((MoveSimple { velocity: vec2(1.,1.), bounce: 0, collide_height: None}, Transform::from_translation(tower_pos)));
-}
\ No newline at end of file
+        let mut fired_at = vec![];
+        for tick in 0..cooldown * 2 {
+            world.resource_mut::<SimTick>().0 = tick;
+            let before = world.query::<&MoveSimple>().iter(&world).count();
+            world.run_system_once(update_effective_stats).unwrap();
+            world.run_system_once(tower_attacks).unwrap();
+            let after = world.query::<&MoveSimple>().iter(&world).count();
+            if after > before { fired_at.push((tick, after - before)); }
+        }
+        assert_eq!(fired_at, vec![(0, 1), (4, 1), (8, 1), (8 + cooldown, 1), (12 + cooldown, 1), (16 + cooldown, 1)]);
+    }
+}
@@ -1,8 +1,8 @@
 use bevy::{math::{ops::log2, vec2}, prelude::*, utils::HashMap};
-use rand::RngCore;
+use serde::{Deserialize, Serialize};
 use std::cmp::min;
 
-use crate::core::{hitbox::HitboxSimple, map::Map, movement::{advance_move_along_road, MoveAlongRoad}};
+use crate::core::{hitbox::HitboxSimple, map::Map, movement::{advance_move_along_road, MoveAlongRoad}, sim_rng::SimRng};
 
 /*
     Helper Functions
@@ -15,11 +15,13 @@ fn first_n_bits_mask(n: u8) -> BIDTree {
 }
 
 /// Create a bloon at the beginning of the given track
-pub fn create_bloon(tier: BloonTier, map: &Map)->(Bloon, Sprite, MoveAlongRoad, Transform, HitboxSimple) {
+pub fn create_bloon(tier: BloonTier, map: &Map, rng: &mut SimRng)->(Bloon, Sprite, MoveAlongRoad, Transform, HitboxSimple) {
+    let mut bloon = Bloon::with(tier, BloonModifier::default());
+    bloon.bid = BloonID::new(rng.next_family());
     return (
-        Bloon::with(tier, BloonModifier::default()),
+        bloon,
         get_bloon_sprite(tier),
-        MoveAlongRoad { target_node: 0, road_pos: 0., waypoint: map.start_pos(), velocity: tier.get_base_speed() },
+        MoveAlongRoad { target_node: 0, road_pos: 0., waypoint: map.start_pos(), velocity: tier.get_base_speed(), reversed: false },
         Transform::from_xyz(map.start_pos().x, map.start_pos().y, 1.),
         HitboxSimple { radius: tier.get_base_hitbox_radius() },
     );
@@ -80,7 +82,7 @@ pub fn lookup_overkill_bloon(bloon: &Bloon, overkill_bloon_map: &HashMap<(BloonT
     if let Some(children) = overkill_bloon_map.get(&(bloon.tier, bloon.hp)) {
         let mut out = vec![];
         for (i, child) in children.iter().enumerate() {
-            out.push(Bloon::with(*child, bloon.modifiers).child_of(bloon, i, children.len()));
+            out.push(Bloon::with(*child, bloon.child_modifiers()).child_of(bloon, i, children.len()));
         }
         return out;
     }
@@ -91,11 +93,11 @@ pub fn lookup_overkill_bloon(bloon: &Bloon, overkill_bloon_map: &HashMap<(BloonT
     Bloon ID
 */
 
-type BIDFamily = u32;
-type BIDTree = u32;
+pub type BIDFamily = u32;
+pub type BIDTree = u32;
 
 /// Uniquily identify bloons
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct BloonID {
     pub family: BIDFamily,
     pub layer: u8,
@@ -110,8 +112,8 @@ impl BloonID {
         let mask = first_n_bits_mask(min_layer);
         return (self.tree & mask) == (other.tree & mask);
     }
-    pub fn new()->Self {
-        return Self { family: rand::rng().next_u32(), layer: 0, tree: 0 };
+    pub fn new(family: BIDFamily)->Self {
+        return Self { family, layer: 0, tree: 0 };
     }
 }
 
@@ -122,7 +124,8 @@ impl BloonID {
 /// Basically damage types and immunities to damage types
 pub type BloonModifier = u16;
 
-enum BloonModifierData {
+#[derive(Clone, Copy)]
+pub enum BloonModifierData {
     Lead = 0b1,
     Purple = 0b10,
     Black = 0b100,
@@ -132,6 +135,7 @@ enum BloonModifierData {
     Fortified = 0b1000000,
 }
 
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum DamageType {
     Normal = 0, // can damage all
     Shatter = BloonModifierData::Lead as isize,
@@ -143,13 +147,58 @@ pub enum DamageType {
     Cold = BloonModifierData::Lead as isize | BloonModifierData::White as isize,
 }
 
+impl DamageType {
+    /// Modifiers of bloons that this damage type can't pop
+    pub fn cannot_pop_modifiers(&self)->BloonModifier {
+        return *self as BloonModifier;
+    }
+}
+
 /// Effects that bloons can have. Duration in game ticks. No duration indicates an instant effect, such as de-fortify.
-#[derive(PartialEq, Clone, Copy)]
+#[derive(PartialEq, Clone, Copy, Serialize, Deserialize)]
 pub enum BloonEffect {
     Weakness { duration: Option<i32>, strength: i32 },
     Speed { duration: Option<i32>, strength: f32 }, // also serves as slow and stun
     BonusIncome { duration: Option<i32>, strength: i32 },
     DamageOverTime { duration: Option<i32>, damage: i32, tick_rate: u32 },
+    Frozen { duration: Option<i32> }, // the bloon has the Frozen modifier and doesn't move until this runs out
+    Glued { duration: Option<i32>, slow: f32, dot: Option<(i32, u32)>, layers: u8 }, // slows like Speed and damages like DamageOverTime (damage, tick_rate); children get it `layers` layers down
+    Decamo, // loses the Camo modifier
+    Delead, // loses the Lead modifier; a lead bloon turns into a black one
+    Deregrow, // loses `BloonPropertyRegrow` (see `event::apply_status_effect`)
+}
+
+impl BloonEffect {
+    /// Ticks left; None for instant effects, which are never kept on a bloon
+    fn duration_mut(&mut self)->Option<&mut i32> {
+        return match self {
+            BloonEffect::Weakness { duration, .. } | BloonEffect::Speed { duration, .. } | BloonEffect::BonusIncome { duration, .. } |
+            BloonEffect::DamageOverTime { duration, .. } | BloonEffect::Frozen { duration } | BloonEffect::Glued { duration, .. } => duration.as_mut(),
+            BloonEffect::Decamo | BloonEffect::Delead | BloonEffect::Deregrow => None,
+        };
+    }
+    /// What this effect multiplies the bloon's speed by
+    fn speed_mult(&self)->f32 {
+        return match *self {
+            BloonEffect::Speed { strength, .. } => strength,
+            BloonEffect::Glued { slow, .. } => slow,
+            BloonEffect::Frozen { .. } => 0.,
+            _ => 1.,
+        };
+    }
+    /// Damage this effect deals this tick (every `tick_rate` ticks)
+    fn tick_damage(&self)->i32 {
+        let (duration, damage, tick_rate) = match *self {
+            BloonEffect::DamageOverTime { duration, damage, tick_rate } | BloonEffect::Glued { duration, dot: Some((damage, tick_rate)), .. } => (duration, damage, tick_rate),
+            _ => return 0,
+        };
+        return if duration.unwrap_or(0) % tick_rate.max(1) as i32 == 0 { damage } else { 0 };
+    }
+    /// Effects without a duration happen once when applied, and aren't kept on the bloon
+    pub fn is_instant(&self)->bool {
+        let mut effect = *self;
+        return effect.duration_mut().is_none();
+    }
 }
 
 /// Every bloon tier has an associated type that determines interactions with damage dealers
@@ -158,11 +207,22 @@ pub enum BloonType {
     Bloon, Blimp, Boss,
 }
 
+impl BloonType {
+    /// How much of a blowback bloons of this type actually take
+    pub fn blowback_mult(&self)->f32 {
+        return match self {
+            BloonType::Bloon => 1.,
+            BloonType::Blimp => 0.25,
+            BloonType::Boss => 0.,
+        };
+    }
+}
+
 /*
     Bloon component and related
 */
 
-#[derive(Component, Clone)]
+#[derive(Component, Clone, Serialize, Deserialize)]
 pub struct Bloon {
     pub bid: BloonID,
     pub hp: i32, // negative hp is overkill amount
@@ -173,26 +233,57 @@ pub struct Bloon {
 
 impl Bloon {
     pub fn with(tier: BloonTier, mut modifiers: BloonModifier)->Bloon {
-        modifiers = match tier {
-            BloonTier::Purple => modifiers | BloonModifierData::Purple as BloonModifier,
-            BloonTier::Black => modifiers | BloonModifierData::Black as BloonModifier,
-            BloonTier::White => modifiers | BloonModifierData::White as BloonModifier,
-            BloonTier::Zebra => modifiers | BloonModifierData::Black as BloonModifier | BloonModifierData::White as BloonModifier,
-            BloonTier::Lead => modifiers | BloonModifierData::Lead as BloonModifier,
-            BloonTier::DDT => modifiers | BloonModifierData::Lead as BloonModifier | BloonModifierData::Camo as BloonModifier,
-            _ => modifiers,
-        };
+        modifiers |= tier.get_base_modifiers();
         let hp_mult = if (modifiers & BloonModifierData::Fortified as BloonModifier) != 0 { tier.get_fortified_hp_mult() } else { 1 };
         return Bloon {
             hp: tier.get_base_hp() * hp_mult,
             tier: tier,
             modifiers: modifiers,
-            bid: BloonID::new(), // make a new and random family id
+            bid: BloonID::new(0), // children inherit the parent's family; new bloons get one from `SimRng` (see `create_bloon`)
             effects: vec![],
         };
     }
     pub fn apply_effect(&mut self, effect: BloonEffect) {
-        self.effects.push(effect);
+        match effect {
+            BloonEffect::Frozen { .. } => self.modifiers |= BloonModifierData::Frozen as BloonModifier,
+            // new glue replaces the old one instead of stacking with it
+            BloonEffect::Glued { .. } => self.remove_glue(),
+            BloonEffect::Decamo => self.modifiers &= !(BloonModifierData::Camo as BloonModifier),
+            BloonEffect::Delead if self.tier == BloonTier::Lead => self.delead(),
+            BloonEffect::Delead => self.modifiers &= !(BloonModifierData::Lead as BloonModifier),
+            _ => {},
+        }
+        if !effect.is_instant() { self.effects.push(effect); }
+    }
+    /// Turn a lead bloon into a black one, keeping the damage it took. It keeps its id, so whatever already hit it won't hit it again.
+    fn delead(&mut self) {
+        let lead = Bloon::with(BloonTier::Lead, self.modifiers);
+        let black = Bloon::with(BloonTier::Black, self.modifiers & !BloonTier::Lead.get_base_modifiers());
+        self.hp = black.hp - (lead.hp - self.hp); // already popped: pops as a black bloon
+        self.tier = BloonTier::Black;
+        self.modifiers = black.modifiers;
+    }
+    /// Modifiers children get: everything but the immunities that come with this bloon's tier (a lead bloon's black children aren't lead).
+    /// Camo is passed on even when it comes with the tier (a DDT's ceramics are camo); what has been stripped off the bloon is gone for the children too.
+    fn child_modifiers(&self)->BloonModifier {
+        let tier_immunities = self.tier.get_base_modifiers() & !(BloonModifierData::Camo as BloonModifier);
+        return self.modifiers & !tier_immunities;
+    }
+    pub fn is_glued(&self)->bool {
+        return self.effects.iter().any(|e| matches!(e, BloonEffect::Glued { .. }));
+    }
+    fn remove_glue(&mut self) {
+        self.effects.retain(|e| !matches!(e, BloonEffect::Glued { .. }));
+    }
+    /// Effects a child gets from its popped parent: glue, while it has layers left.
+    /// Children are never frozen, even though they inherit the parent's modifiers.
+    pub fn inherit_effects(&mut self, parent: &Bloon) {
+        self.modifiers &= !(BloonModifierData::Frozen as BloonModifier);
+        for effect in &parent.effects {
+            let BloonEffect::Glued { duration, slow, dot, layers } = *effect else { continue; };
+            if layers == 0 { continue; }
+            self.apply_effect(BloonEffect::Glued { duration, slow, dot, layers: layers - 1 });
+        }
     }
     pub fn get_child_bloons(&self)->Vec<Bloon> {
         let base_children = self.tier.get_base_child_bloons();
@@ -201,7 +292,7 @@ impl Bloon {
         let child_num = base_children.len();
         for ch in base_children {
             // TODO: Right now fortified will propagate through the red bloon; should drop at ceram level
-            let mut child = Bloon::with(ch, self.modifiers);
+            let mut child = Bloon::with(ch, self.child_modifiers());
             child.bid.family = self.bid.family;
             child.bid.tree = self.bid.tree;
             child.bid.layer = self.bid.layer;
@@ -262,13 +353,25 @@ impl Eq for Bloon {}
 */
 
 /// The bloon tier determines base stats (speed, hp, etc) as well as children and type (bloon, blimp, boss)
-#[derive(PartialEq, Eq, PartialOrd, Ord, Default, Clone, Copy, Hash)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Default, Clone, Copy, Hash, Serialize, Deserialize)]
 pub enum BloonTier {
     #[default]
     Red, Blue, Green, Yellow, Pink, Purple, Black, White, Zebra, Lead, Rainbow, Ceramic, MOAB, BFB, ZOMG, DDT, BAD,
 }
 
 impl BloonTier {
+    /// Modifiers every bloon of this tier has
+    pub fn get_base_modifiers(&self)->BloonModifier {
+        return match self {
+            BloonTier::Purple => BloonModifierData::Purple as BloonModifier,
+            BloonTier::Black => BloonModifierData::Black as BloonModifier,
+            BloonTier::White => BloonModifierData::White as BloonModifier,
+            BloonTier::Zebra => BloonModifierData::Black as BloonModifier | BloonModifierData::White as BloonModifier,
+            BloonTier::Lead => BloonModifierData::Lead as BloonModifier,
+            BloonTier::DDT => BloonModifierData::Lead as BloonModifier | BloonModifierData::Camo as BloonModifier,
+            _ => 0,
+        };
+    }
     pub fn get_type(&self)->BloonType {
         match self {
             BloonTier::MOAB | BloonTier::BFB | BloonTier::ZOMG | BloonTier::DDT | BloonTier::BAD => BloonType::Blimp,
@@ -373,7 +476,7 @@ impl BloonTier {
     Bloon properties (it's sad and lonely here)
 */
 
-#[derive(Component)]
+#[derive(Component, Clone, Serialize, Deserialize)]
 pub struct BloonPropertyRegrow {
     cooldown_total: i32,
     cooldown_left: i32,
@@ -384,11 +487,33 @@ pub struct BloonPropertyRegrow {
     Misc systems
 */
 
+/// Count down bloon effects and apply the lasting ones: Speed effects (and glue) multiply the bloon's base speed, frozen bloons don't move,
+/// and DamageOverTime (and corrosive glue) damages it every `tick_rate` ticks. A bloon thaws (loses the Frozen modifier) once its last Frozen effect runs out.
+pub fn tick_bloon_effects(mut bloons: Query<(&mut Bloon, &mut MoveAlongRoad)>) {
+    for (mut bloon, mut re) in &mut bloons {
+        let speed: f32 = bloon.effects.iter().map(|e| e.speed_mult()).product();
+        let damage: i32 = bloon.effects.iter().map(|e| e.tick_damage()).sum();
+        re.velocity = bloon.tier.get_base_speed() * speed;
+        if bloon.effects.is_empty() && bloon.modifiers & BloonModifierData::Frozen as BloonModifier == 0 { continue; }
+        bloon.hp -= damage;
+        bloon.effects.retain_mut(|e| {
+            let Some(duration) = e.duration_mut() else { return false; };
+            *duration -= 1;
+            return *duration > 0;
+        });
+        if !bloon.effects.iter().any(|e| matches!(e, BloonEffect::Frozen { .. })) {
+            bloon.modifiers &= !(BloonModifierData::Frozen as BloonModifier);
+        }
+    }
+}
+
 /// Check if bloons are dead. If yes, spawn children or despawn. Should happen only after the bloons have moved this turn.
 /// Big and ugly, sorry, can't do much about that.
-pub fn pop_bloons(mut cmd: Commands, map: Res<Map>, bloons: Query<(Entity, &Bloon, &MoveAlongRoad, &Transform)>, overkill_map: Res<OverkillLookupTable>) {
+/// Children regrow if the parent did (the ones that replace the parent keep its `BloonPropertyRegrow`, the rest get a copy).
+pub fn pop_bloons(mut cmd: Commands, map: Res<Map>, bloons: Query<(Entity, &Bloon, &MoveAlongRoad, &Transform, Option<&BloonPropertyRegrow>)>, overkill_map: Res<OverkillLookupTable>) {
     let mut new_bloons: Vec<(Bloon, MoveAlongRoad, HitboxSimple, Transform, Sprite)> = vec![];
-    for (e, bloon, re, pos) in &bloons {
+    let mut new_regrow_bloons: Vec<(Bloon, MoveAlongRoad, HitboxSimple, Transform, Sprite, BloonPropertyRegrow)> = vec![];
+    for (e, bloon, re, pos, regrow) in &bloons {
         if bloon.hp > 0 { continue; }
         // Decide whether layer skip is necessary or not
         let child_bloons = if bloon.hp == 0 { bloon.get_child_bloons() } else { calculate_overkill(bloon, &**overkill_map) };
@@ -396,7 +521,8 @@ pub fn pop_bloons(mut cmd: Commands, map: Res<Map>, bloons: Query<(Entity, &Bloo
             0 => { cmd.entity(e).despawn(); },
             1 => {
                 // let mut child = child_bloons.remove(0); // requires to make `child_bloons` mut and mut is stinky
-                let child = child_bloons.into_iter().next().unwrap();
+                let mut child = child_bloons.into_iter().next().unwrap();
+                child.inherit_effects(bloon);
                 let child_re = re.clone_with_velocity(child.tier.get_base_speed());
                 let child_hb = HitboxSimple { radius: child.tier.get_base_hitbox_radius() };
                 let child_sprite = get_bloon_sprite(child.tier); // TODO: sprites should not be here
@@ -404,7 +530,8 @@ pub fn pop_bloons(mut cmd: Commands, map: Res<Map>, bloons: Query<(Entity, &Bloo
             },
             _ => {
                 let mut i = 0;
-                for child in child_bloons {
+                for mut child in child_bloons {
+                    child.inherit_effects(bloon);
                     let mut child_re = re.clone_with_velocity(child.tier.get_base_speed());
                     let child_hb = HitboxSimple { radius: child.tier.get_base_hitbox_radius() };
                     let child_sprite = get_bloon_sprite(child.tier); // TODO: sprites should not be here
@@ -414,13 +541,17 @@ pub fn pop_bloons(mut cmd: Commands, map: Res<Map>, bloons: Query<(Entity, &Bloo
                     } else {
                         let mut child_transform = pos.clone();
                         advance_move_along_road(25.0 * i as f32, &*map, &mut child_re, &mut child_transform);
-                        new_bloons.push((
-                            child,
-                            child_re,
-                            child_hb,
-                            child_transform,
-                            child_sprite,
-                        ));
+                        if let Some(regrow) = regrow {
+                            new_regrow_bloons.push((child, child_re, child_hb, child_transform, child_sprite, regrow.clone()));
+                        } else {
+                            new_bloons.push((
+                                child,
+                                child_re,
+                                child_hb,
+                                child_transform,
+                                child_sprite,
+                            ));
+                        }
                     }
                     i += 1;
                 }
@@ -428,6 +559,7 @@ pub fn pop_bloons(mut cmd: Commands, map: Res<Map>, bloons: Query<(Entity, &Bloo
         }
     }
     cmd.spawn_batch(new_bloons);
+    cmd.spawn_batch(new_regrow_bloons);
 }
 
 // TODO: remove from here
@@ -451,4 +583,93 @@ pub fn get_bloon_sprite(tier: BloonTier)->Sprite {
         BloonTier::DDT => Sprite::from_color(Color::Srgba(Srgba { red: 0.1, green: 0.1, blue: 0.1, alpha: 1. }), vec2(120., 120.)),
         BloonTier::BAD => Sprite::from_color(Color::Srgba(Srgba { red: 0.9, green: 0.3, blue: 0.4, alpha: 1. }), vec2(200., 200.)),
     };
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::hit_set::HitSet;
+
+    fn glue(duration: i32, layers: u8)->BloonEffect {
+        return BloonEffect::Glued { duration: Some(duration), slow: 0.5, dot: Some((1, 10)), layers };
+    }
+
+    fn has(bloon: &Bloon, modifier: BloonModifierData)->bool {
+        return bloon.modifiers & modifier as BloonModifier != 0;
+    }
+
+    #[test]
+    fn children_lose_tier_immunities() {
+        let children = Bloon::with(BloonTier::Lead, 0).get_child_bloons();
+        assert!(children.iter().all(|c| c.tier == BloonTier::Black && !has(c, BloonModifierData::Lead)));
+        let grandchildren = children[0].get_child_bloons();
+        assert!(grandchildren.iter().all(|c| c.tier == BloonTier::Pink && c.modifiers == 0));
+        let children = Bloon::with(BloonTier::Zebra, 0).get_child_bloons();
+        assert!(children.iter().all(|c| c.modifiers == c.tier.get_base_modifiers()));
+    }
+
+    #[test]
+    fn children_keep_camo() {
+        let ddt = Bloon::with(BloonTier::DDT, 0);
+        assert!(ddt.get_child_bloons().iter().all(|c| has(c, BloonModifierData::Camo) && !has(c, BloonModifierData::Lead)));
+        let camo_lead = Bloon::with(BloonTier::Lead, BloonModifierData::Camo as BloonModifier);
+        assert!(camo_lead.get_child_bloons().iter().all(|c| has(c, BloonModifierData::Camo)));
+    }
+
+    #[test]
+    fn decamo_reaches_grandchildren() {
+        let mut ceramic = Bloon::with(BloonTier::Ceramic, BloonModifierData::Camo as BloonModifier);
+        ceramic.apply_effect(BloonEffect::Decamo);
+        assert!(!has(&ceramic, BloonModifierData::Camo));
+        let children = ceramic.get_child_bloons();
+        assert!(children.iter().all(|c| !has(c, BloonModifierData::Camo)));
+        assert!(children[0].get_child_bloons().iter().all(|c| !has(c, BloonModifierData::Camo)));
+    }
+
+    #[test]
+    fn delead_turns_lead_into_black() {
+        let mut lead = Bloon::with(BloonTier::Lead, BloonModifierData::Camo as BloonModifier);
+        lead.hp -= 1;
+        lead.apply_effect(BloonEffect::Delead);
+        assert!(lead.tier == BloonTier::Black && !has(&lead, BloonModifierData::Lead) && has(&lead, BloonModifierData::Camo));
+        assert_eq!(lead.hp, BloonTier::Black.get_base_hp() - 1);
+        assert!(lead.get_child_bloons().iter().all(|c| !has(c, BloonModifierData::Lead) && has(c, BloonModifierData::Camo)));
+    }
+
+    #[test]
+    fn deleaded_bloon_is_not_hit_twice() {
+        let mut lead = Bloon::with(BloonTier::Lead, 0);
+        lead.bid = BloonID::new(7);
+        let mut hits = HitSet::default();
+        hits.insert(&lead.bid);
+        lead.apply_effect(BloonEffect::Delead);
+        assert!(hits.has_hit(&lead.bid));
+        assert!(lead.get_child_bloons().iter().all(|c| hits.has_hit(&c.bid)));
+    }
+
+    #[test]
+    fn reglue_keeps_freeze() {
+        let mut bloon = Bloon::with(BloonTier::Red, 0);
+        bloon.apply_effect(BloonEffect::Frozen { duration: Some(60) });
+        bloon.apply_effect(glue(60, 0));
+        bloon.apply_effect(glue(60, 0));
+        assert_eq!(bloon.effects.iter().filter(|e| matches!(e, BloonEffect::Frozen { .. })).count(), 1);
+        assert_eq!(bloon.effects.iter().filter(|e| matches!(e, BloonEffect::Glued { .. })).count(), 1);
+        assert_eq!(bloon.effects.iter().map(|e| e.speed_mult()).product::<f32>(), 0.);
+    }
+
+    #[test]
+    fn children_get_glue_but_not_freeze() {
+        let mut parent = Bloon::with(BloonTier::Green, 0);
+        parent.apply_effect(BloonEffect::Frozen { duration: Some(60) });
+        parent.apply_effect(glue(60, 1));
+        let mut child = parent.get_child_bloons().remove(0);
+        child.inherit_effects(&parent);
+        assert_eq!(child.modifiers & BloonModifierData::Frozen as BloonModifier, 0);
+        assert!(child.effects == vec![glue(60, 0)]);
+
+        // out of layers
+        let mut grandchild = child.get_child_bloons().remove(0);
+        grandchild.inherit_effects(&child);
+        assert!(grandchild.effects.is_empty());
+    }
+}
@@ -0,0 +1,213 @@
+use bevy::{prelude::*, utils::HashSet};
+use serde::{Deserialize, Serialize};
+
+use crate::core::{bloon::BloonModifier, game::RoundEndEvent, subtower::Subtower, tower::{Tower, TowerEffect, TowerId}};
+
+/*
+    Buffs
+*/
+
+/// Identifies what a buff is, so that buffs of the same kind can stack or override each other
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum BuffKind {
+    VillageRange,
+    BerserkerBrew, // alch
+    JungleDrums,
+    RadarScanner,
+    RapidShot, // quincy
+    FanClub,
+    CallToArms, // village
+}
+
+/// Who a buff reaches
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BuffScope {
+    Global, // every tower (call to arms)
+    Area,   // every tower in range of the source (village)
+    Single, // one tower, given directly (alch, overclock)
+}
+
+/// What happens when a tower gets several buffs of the same kind from different sources
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BuffStacking {
+    Stack,    // all of them count (mermonkey, ninja)
+    Override, // only the most recently applied one counts (overclock)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BuffDuration {
+    Ticks(u32),
+    Rounds(u32),
+    Permanent, // until removed: the source is sold, or the tower leaves its area
+}
+
+/// An effect on a tower, and the tower that gave it (None if it didn't come from a tower).
+/// Buffs are removed from everyone when their source is sold.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TowerBuff {
+    pub source: Option<TowerId>,
+    pub kind: BuffKind,
+    pub scope: BuffScope,
+    pub stacking: BuffStacking,
+    pub duration: BuffDuration,
+    pub effect: TowerEffect,
+}
+
+/// A buff that tower targeted attacks give out (alch, or pulses to everything in range)
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BuffDef {
+    pub kind: BuffKind,
+    pub stacking: BuffStacking,
+    pub duration: BuffDuration,
+    pub effect: TowerEffect,
+}
+
+/// A global or area buff a tower gives out for as long as it exists. Part of the tower prototype.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BuffAura {
+    pub kind: BuffKind,
+    pub scope: BuffScope, // Global or Area
+    #[serde(default)]
+    pub range: f32, // Area only
+    pub stacking: BuffStacking,
+    pub effect: TowerEffect,
+}
+
+/// Every buff of a tower added up. Recomputed whenever a buff is added or removed, so attacks never have to go through the buff list.
+#[derive(Clone, PartialEq)]
+pub struct BuffStats {
+    pub range_mult: f32,
+    pub damage: i32,
+    pub pierce: i32,
+    pub attack_rate_mult: f32, // multiplies the ticks between shots
+    pub detection: BloonModifier, // modifiers it can now target (camo)
+    pub can_pop: BloonModifier, // modifiers it can now pop (lead, purple, ...)
+}
+
+impl Default for BuffStats {
+    fn default()->Self {
+        return BuffStats { range_mult: 1., damage: 0, pierce: 0, attack_rate_mult: 1., detection: 0, can_pop: 0 };
+    }
+}
+
+impl BuffStats {
+    pub fn from_buffs(buffs: &[TowerBuff])->Self {
+        let mut stats = BuffStats::default();
+        for (i, buff) in buffs.iter().enumerate() {
+            // an overriding buff only counts if it's the most recent one of its kind
+            if buff.stacking == BuffStacking::Override && buffs[i+1..].iter().any(|b| b.kind == buff.kind) { continue; }
+            match buff.effect {
+                TowerEffect::Range { strength } => stats.range_mult += strength,
+                TowerEffect::Damage { strength } => stats.damage += strength,
+                TowerEffect::Pierce { strength } => stats.pierce += strength,
+                TowerEffect::AttackRate { strength } => stats.attack_rate_mult *= strength,
+                TowerEffect::DetectionBuff { added } => stats.detection |= added,
+                TowerEffect::DamageTypeBuff { added } => stats.can_pop |= added,
+            }
+        }
+        return stats;
+    }
+}
+
+/// Give a single buff to a tower from anywhere that only has `Commands` (attack functions)
+pub fn give_buff(cmd: &mut Commands, target: Entity, buff: TowerBuff) {
+    cmd.queue(move |world: &mut World| {
+        if let Some(mut tower) = world.get_mut::<Tower>(target) {
+            tower.add_buff(buff);
+        }
+    });
+}
+
+/// Buffs that only last while an aura reaches the tower. Area buffs pulsed out by attacks have a duration instead.
+fn is_aura_buff(buff: &TowerBuff)->bool {
+    return buff.scope != BuffScope::Single && buff.duration == BuffDuration::Permanent;
+}
+
+/*
+    Systems
+*/
+
+/// Give out global and area buffs to the towers they reach, and take them away from towers they don't reach anymore
+/// (out of range, or the source is gone)
+pub fn update_aura_buffs(mut towers: Query<(&TowerId, &Transform, &mut Tower), Without<Subtower>>) {
+    let mut auras: Vec<(TowerId, Vec2, BuffAura)> = towers.iter()
+        .flat_map(|(id, pos, tower)| tower.get_auras().iter().map(move |aura| (*id, pos.translation.truncate(), aura.clone())))
+        .collect();
+    auras.sort_by_key(|(id, _, _)| *id);
+
+    for (id, pos, mut tower) in &mut towers {
+        let reached: Vec<&(TowerId, Vec2, BuffAura)> = auras.iter().filter(|(source, source_pos, aura)| {
+            *source != *id && (aura.scope == BuffScope::Global || source_pos.distance(pos.translation.truncate()) <= aura.range)
+        }).collect();
+        let keep: HashSet<(TowerId, BuffKind)> = reached.iter().map(|(source, _, aura)| (*source, aura.kind)).collect();
+        let reaches = |b: &TowerBuff| !is_aura_buff(b) || b.source.is_some_and(|s| keep.contains(&(s, b.kind)));
+        if !tower.get_buffs().iter().all(reaches) {
+            tower.retain_buffs(reaches);
+        }
+        for (source, _, aura) in reached {
+            if tower.has_buff(*source, aura.kind) { continue; }
+            tower.add_buff(TowerBuff { source: Some(*source), kind: aura.kind, scope: aura.scope, stacking: aura.stacking, duration: BuffDuration::Permanent, effect: aura.effect.clone() });
+        }
+    }
+}
+
+/// Count down tick based buffs (subtowers copy theirs from the parent)
+pub fn tick_buffs(mut towers: Query<&mut Tower, Without<Subtower>>) {
+    for mut tower in &mut towers {
+        if tower.get_buffs().iter().any(|b| matches!(b.duration, BuffDuration::Ticks(_))) {
+            tower.count_down_buffs(|d| if let BuffDuration::Ticks(t) = d { *t = t.saturating_sub(1); });
+        }
+    }
+}
+
+/// Count down round based buffs when a round ends
+pub fn round_buffs(mut towers: Query<&mut Tower, Without<Subtower>>, mut round_end_er: EventReader<RoundEndEvent>) {
+    for _ in round_end_er.read() {
+        for mut tower in &mut towers {
+            if tower.get_buffs().iter().any(|b| matches!(b.duration, BuffDuration::Rounds(_))) {
+                tower.count_down_buffs(|d| if let BuffDuration::Rounds(r) = d { *r = r.saturating_sub(1); });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use crate::core::{tower::TowerType, tower_def::TowerDefinitions, upgradable::Upgradable};
+
+    use super::*;
+
+    fn has_village_buff(world: &World, e: Entity)->bool {
+        return world.get::<Tower>(e).unwrap().has_buff(TowerId(0), BuffKind::VillageRange);
+    }
+
+    fn move_to(world: &mut World, e: Entity, x: f32) {
+        world.get_mut::<Transform>(e).unwrap().translation.x = x;
+        world.run_system_once(update_aura_buffs).unwrap();
+    }
+
+    #[test]
+    fn aura_buffs_follow_range_and_source() {
+        let defs = TowerDefinitions::load_embedded();
+        let mut world = World::new();
+        // the village's radius is 120
+        let village = world.spawn((Tower::from(&defs, TowerType::Village, Upgradable::None), TowerId(0), Transform::default())).id();
+        let dart = world.spawn((Tower::from(&defs, TowerType::DartMonkey, Upgradable::None), TowerId(1), Transform::from_xyz(200., 0., 0.))).id();
+        world.run_system_once(update_aura_buffs).unwrap();
+        assert!(!has_village_buff(&world, dart));
+        move_to(&mut world, dart, 100.);
+        assert!(has_village_buff(&world, dart));
+        assert_eq!(world.get::<Tower>(dart).unwrap().get_buffs().len(), 1);
+        move_to(&mut world, dart, 130.);
+        assert!(!has_village_buff(&world, dart));
+        move_to(&mut world, dart, 100.);
+        assert!(has_village_buff(&world, dart));
+        // sold
+        world.despawn(village);
+        world.run_system_once(update_aura_buffs).unwrap();
+        assert!(!has_village_buff(&world, dart));
+        assert!(world.get::<Tower>(dart).unwrap().get_buffs().is_empty());
+    }
+}
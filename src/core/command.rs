@@ -0,0 +1,72 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::core::{ability::AbilityTargetCommand, bloon::{create_bloon, BloonTier}, event::GlobalDamageEvent, game::RoundEndSweep, map::Map, projectile::simple_projectile, sim_rng::SimRng, tower::{TowerId, TowerType}, zombie::create_zombie};
+
+/*
+    Player commands
+*/
+
+/// Everything the player can do to the simulation. Input never touches the simulation directly;
+/// it queues one of these, and it gets applied (and recorded) at the start of the next fixed tick.
+#[derive(Event, Clone, Serialize, Deserialize)]
+pub enum PlayerCommand {
+    PlaceTower { tower_type: TowerType, pos: Vec2 },
+    SetTargeting { tower: TowerId, mode: usize },
+    UpgradeTower { tower: TowerId, path: u8 }, // path is 1, 2 or 3
+    SellTower { tower: TowerId },
+    SetSubtowerTarget { tower: TowerId, target: Option<Vec2> }, // where the tower's movable subtowers fly to; None locks them in place
+    UseAbility { tower: TowerId, ability: String, target: AbilityTargetCommand }, // ability is the name of one of the tower's `Abilities`
+    StartRound,
+    SetSpeed { hz: f64 },
+    SetRoundEndSweep { sweep: RoundEndSweep },
+    // debug/testing commands
+    SpawnBloon { tier: BloonTier },
+    SpawnZombie { tier: BloonTier, damage: i32 },
+    GlobalDamage { damage: i32 },
+    SpawnProjectile { velocity: Vec2, damage: i32, pierce: u32 },
+    SprayProjectiles { velocity: Vec2, damage: i32, pierce: u32 },
+}
+
+/// Commands queued by input, waiting for the next fixed tick
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct PendingPlayerCommands(pub Vec<PlayerCommand>);
+
+/*
+    Systems
+*/
+
+/// Apply debug commands (spawning bloons, zombies and projectiles by hand)
+pub fn apply_debug_commands(mut cmd: Commands, mut commands: EventReader<PlayerCommand>, map: Res<Map>, mut rng: ResMut<SimRng>, mut global_damage_ew: EventWriter<GlobalDamageEvent>) {
+    for command in commands.read() {
+        match command {
+            PlayerCommand::SpawnBloon { tier } => {
+                cmd.spawn(create_bloon(*tier, &*map, &mut *rng));
+            },
+            PlayerCommand::SpawnZombie { tier, damage } => {
+                let Some(zombie) = create_zombie(*tier, *damage, &*map) else { continue; };
+                cmd.spawn(zombie);
+            },
+            PlayerCommand::GlobalDamage { damage } => {
+                global_damage_ew.send(GlobalDamageEvent { damage: *damage, status_effect: None });
+            },
+            PlayerCommand::SpawnProjectile { velocity, damage, pierce } => {
+                cmd.spawn(simple_projectile(*damage, *pierce, *velocity));
+            },
+            PlayerCommand::SprayProjectiles { velocity, damage, pierce } => {
+                let (vx, vy) = (velocity.x, velocity.y);
+                for i in 0..100 {
+                    let offset = i as f32 / 20.;
+                    cmd.spawn_batch(vec![
+                        simple_projectile(*damage, *pierce, Vec2::new(vx + offset, vy + offset)),
+                        simple_projectile(*damage, *pierce, Vec2::new(vx + 5. + offset, vy + offset)),
+                        simple_projectile(*damage, *pierce, Vec2::new(vx - 5. + offset, vy + offset)),
+                        simple_projectile(*damage, *pierce, Vec2::new(vx + offset, vy + 5. + offset)),
+                        simple_projectile(*damage, *pierce, Vec2::new(vx + offset, vy - 5. + offset)),
+                    ]);
+                }
+            },
+            _ => {},
+        }
+    }
+}
@@ -1,27 +1,39 @@
 use bevy::prelude::*;
 
-use crate::core::bloon::{Bloon, BloonEffect};
+use crate::core::bloon::{get_bloon_sprite, Bloon, BloonEffect, BloonPropertyRegrow};
+use crate::core::hitbox::HitboxSimple;
+
+/// Apply a status effect to a bloon, including the parts that aren't in the `Bloon` component:
+/// deregrow removes `BloonPropertyRegrow`, and a bloon that changed tier (delead) gets the new tier's sprite and hitbox
+pub fn apply_status_effect(cmd: &mut Commands, e: Entity, bloon: &mut Bloon, effect: BloonEffect) {
+    let tier = bloon.tier;
+    bloon.apply_effect(effect);
+    if effect == BloonEffect::Deregrow { cmd.entity(e).remove::<BloonPropertyRegrow>(); }
+    if bloon.tier != tier {
+        cmd.entity(e).insert((get_bloon_sprite(bloon.tier), HitboxSimple { radius: bloon.tier.get_base_hitbox_radius() })); // TODO: sprites should not be here
+    }
+}
 
 /// A system that applies a global damage effect on all active bloons
-pub fn global_damage_effects(mut bloons: Query<&mut Bloon>, mut global_damage_ev: EventReader<GlobalDamageEvent>) {
+pub fn global_damage_effects(mut cmd: Commands, mut bloons: Query<(Entity, &mut Bloon)>, mut global_damage_ev: EventReader<GlobalDamageEvent>) {
     for ev in global_damage_ev.read() {
-        for mut bloon in &mut bloons {
+        for (e, mut bloon) in &mut bloons {
             // bloon.hp -= ev.damage;
             bloon.hp = 0;
             if let Some(effect) = &ev.status_effect {
-                bloon.apply_effect(*effect);
+                apply_status_effect(&mut cmd, e, &mut bloon, *effect);
             }
         }
     }
 }
 
 /// Apply queued damage to bloons.
-pub fn apply_bloon_damage(mut bloons: Query<&mut Bloon>, mut damage_er: EventReader<BloonDamageEvent>) {
+pub fn apply_bloon_damage(mut cmd: Commands, mut bloons: Query<&mut Bloon>, mut damage_er: EventReader<BloonDamageEvent>) {
     for ev in damage_er.read() {
         if let Ok(mut bloon) = bloons.get_mut(ev.bloon) {
             bloon.hp -= ev.damage;
-            if let Some(effect) = &ev.status_effect {
-                bloon.apply_effect(*effect);
+            for effect in &ev.status_effects {
+                apply_status_effect(&mut cmd, ev.bloon, &mut bloon, *effect);
             }
         }
     }
@@ -42,6 +54,6 @@ pub struct GlobalDamageEvent {
 /// Damage a specific bloon
 pub struct BloonDamageEvent {
     pub damage: i32,
-    pub status_effect: Option<BloonEffect>,
+    pub status_effects: Vec<BloonEffect>,
     pub bloon: Entity,
 }
\ No newline at end of file
@@ -0,0 +1,180 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::core::{bloon::Bloon, command::PlayerCommand};
+
+/*
+    Game state
+*/
+
+/// Number of fixed update ticks since the game started. Everything in the simulation is timed in these.
+#[derive(Resource, Default, Clone, Copy)]
+pub struct SimTick(pub usize);
+
+/// Round, money and lives of the current game
+#[derive(Resource, Clone, Serialize, Deserialize)]
+pub struct GameState {
+    pub round: u32,
+    pub round_active: bool,
+    pub cash: i64,
+    pub lives: i32,
+    pub next_tower_id: u32, // towers get stable ids so that replays and saves can refer to them
+    #[serde(default)]
+    pub next_projectile_seq: u64, // last `DamageDealer::seq` given out
+    pub sell_percent: i64, // how much of what was spent on a tower selling it gives back
+    pub round_end_sweep: RoundEndSweep,
+    #[serde(default)]
+    pub round_ticks: u32, // ticks since the round started
+    #[serde(default)]
+    pub round_had_bloons: bool, // whether any bloon was around during the round
+}
+
+impl Default for GameState {
+    fn default()->Self {
+        return GameState { round: 0, round_active: false, cash: 650, lives: 150, next_tower_id: 0, next_projectile_seq: 0, sell_percent: 70, round_end_sweep: RoundEndSweep::default(), round_ticks: 0, round_had_bloons: false };
+    }
+}
+
+/// What happens to whatever is left lying around when a round ends
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RoundEndSweep {
+    pub collect_items: bool, // collect leftover bananas etc (otherwise they're lost)
+    pub clear_lifetime_under: Option<i32>, // despawn projectiles with less than this many ticks to live, unless they have a `LifetimeRound`
+}
+
+impl RoundEndSweep {
+    /// Leave everything lying around for the next round
+    pub fn off()->Self {
+        return RoundEndSweep { collect_items: false, clear_lifetime_under: None };
+    }
+    pub fn is_off(&self)->bool {
+        return !self.collect_items && self.clear_lifetime_under.is_none();
+    }
+}
+
+impl Default for RoundEndSweep {
+    fn default()->Self {
+        return RoundEndSweep { collect_items: true, clear_lifetime_under: Some(BASE_TICK_HZ as i32) };
+    }
+}
+
+/// Default fixed update rate; speed changes are multiples of it
+pub const BASE_TICK_HZ: f64 = 64.;
+/// A round without any bloons still lasts this many ticks
+pub const MIN_ROUND_TICKS: u32 = 64;
+
+/*
+    Events
+*/
+
+#[derive(Event)]
+/// A new round has started
+pub struct RoundStartEvent {
+    pub round: u32,
+}
+
+#[derive(Event)]
+/// The last bloon of the round is gone
+pub struct RoundEndEvent {
+    pub round: u32,
+}
+
+/*
+    Systems
+*/
+
+pub fn advance_tick(mut tick: ResMut<SimTick>) {
+    tick.0 += 1;
+}
+
+/// Apply round, game speed and round end sweep commands
+pub fn apply_game_commands(mut commands: EventReader<PlayerCommand>, mut game: ResMut<GameState>, mut time: ResMut<Time<Fixed>>, mut round_start_ew: EventWriter<RoundStartEvent>) {
+    for command in commands.read() {
+        match command {
+            PlayerCommand::StartRound => {
+                if game.round_active { continue; }
+                game.round += 1;
+                game.round_active = true;
+                game.round_ticks = 0;
+                game.round_had_bloons = false;
+                round_start_ew.send(RoundStartEvent { round: game.round });
+            },
+            PlayerCommand::SetSpeed { hz } => {
+                if *hz > 0. { time.set_timestep_hz(*hz); }
+            },
+            PlayerCommand::SetRoundEndSweep { sweep } => {
+                game.round_end_sweep = sweep.clone();
+            },
+            _ => {},
+        }
+    }
+}
+
+/// End the round once every bloon is gone, but not before any bloon showed up or `MIN_ROUND_TICKS` passed
+pub fn check_round_end(mut game: ResMut<GameState>, bloons: Query<(), With<Bloon>>, mut round_end_ew: EventWriter<RoundEndEvent>) {
+    if !game.round_active { return; }
+    game.round_ticks += 1;
+    if !bloons.is_empty() {
+        game.round_had_bloons = true;
+    } else if game.round_had_bloons || game.round_ticks >= MIN_ROUND_TICKS {
+        game.round_active = false;
+        round_end_ew.send(RoundEndEvent { round: game.round });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::{bloon::BloonTier, command::PendingPlayerCommands, replay::{headless_app, step_headless}};
+
+    use super::*;
+
+    fn started_app()->App {
+        let mut app = headless_app(0, 1, false);
+        step_headless(&mut app);
+        return app;
+    }
+
+    fn queue(app: &mut App, command: PlayerCommand) {
+        app.world_mut().resource_mut::<PendingPlayerCommands>().push(command);
+        step_headless(app);
+    }
+
+    fn round_active(app: &App)->bool {
+        return app.world().resource::<GameState>().round_active;
+    }
+
+    #[test]
+    fn empty_round_lasts_the_minimum() {
+        let mut app = started_app();
+        queue(&mut app, PlayerCommand::StartRound);
+        for _ in 1..MIN_ROUND_TICKS {
+            assert!(round_active(&app));
+            step_headless(&mut app);
+        }
+        assert!(!round_active(&app));
+        assert_eq!(app.world().resource::<GameState>().round, 1);
+    }
+
+    #[test]
+    fn round_ends_with_its_last_bloon() {
+        let mut app = started_app();
+        queue(&mut app, PlayerCommand::StartRound);
+        queue(&mut app, PlayerCommand::SpawnBloon { tier: BloonTier::Red });
+        for _ in 0..MIN_ROUND_TICKS { step_headless(&mut app); }
+        assert!(round_active(&app));
+        let bloons: Vec<Entity> = app.world_mut().query_filtered::<Entity, With<Bloon>>().iter(app.world()).collect();
+        for e in bloons { app.world_mut().despawn(e); }
+        step_headless(&mut app);
+        assert!(!round_active(&app));
+    }
+
+    #[test]
+    fn restarting_does_not_skip_rounds() {
+        let mut app = started_app();
+        for _ in 0..5 {
+            queue(&mut app, PlayerCommand::StartRound);
+            queue(&mut app, PlayerCommand::StartRound);
+        }
+        assert_eq!(app.world().resource::<GameState>().round, 1);
+    }
+}
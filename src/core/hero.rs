@@ -0,0 +1,77 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::core::{game::{GameState, RoundEndEvent}, subtower::{update_subtowers, Subtower}, tower::{Tower, TowerId}, tower_def::{TowerDefinitions, UpgradeDef}, upgradable::Upgradable};
+
+pub const MAX_HERO_LEVEL: u8 = 20;
+
+/*
+    Hero data
+*/
+
+/// What makes a tower type a hero: how much XP each level takes, and what each level gives
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HeroDef {
+    pub xp_curve: Vec<u64>, // XP needed to go from level n+1 to n+2 (so 19 entries)
+    pub levels: Vec<UpgradeDef>, // what levels 2-20 add; `cost` is unused
+}
+
+impl HeroDef {
+    /// The level a hero with `xp` total XP is at
+    pub fn level_for(&self, xp: u64)->u8 {
+        let mut level = 1;
+        let mut needed = 0;
+        for step in self.xp_curve.iter().take(MAX_HERO_LEVEL as usize - 1) {
+            needed += step;
+            if xp < needed { break; }
+            level += 1;
+        }
+        return level;
+    }
+}
+
+/// XP every hero gets for completing a round
+pub fn round_xp(round: u32)->u64 {
+    let round = round as u64;
+    return match round {
+        0..=20 => 20 * round + 20,
+        21..=50 => 40 * round - 380,
+        _ => 90 * round - 2880,
+    };
+}
+
+/// XP of a hero; its level is kept in the tower's `Upgradable::Hero20`
+#[derive(Component, Clone, Default, Serialize, Deserialize)]
+pub struct Hero {
+    pub xp: u64,
+}
+
+/*
+    Events
+*/
+
+#[derive(Event)]
+/// A hero levelled up (for the UI)
+pub struct HeroLevelUpEvent {
+    pub id: TowerId,
+    pub level: u8,
+}
+
+/*
+    Systems
+*/
+
+/// Give heroes XP for completed rounds and level them up
+pub fn hero_xp(mut cmd: Commands, mut round_end_er: EventReader<RoundEndEvent>, defs: Res<TowerDefinitions>, mut game: ResMut<GameState>, mut heroes: Query<(Entity, &TowerId, &mut Hero, &mut Tower), Without<Subtower>>, children: Query<&Children>, mut subtowers: Query<(&mut Subtower, &Tower)>, mut level_up_ew: EventWriter<HeroLevelUpEvent>) {
+    for ev in round_end_er.read() {
+        for (e, id, mut hero, mut tower) in &mut heroes {
+            let Some(hero_def) = defs.get(&tower.get_tower_type()).and_then(|def| def.hero.as_ref()) else { continue; };
+            hero.xp += round_xp(ev.round);
+            let level = hero_def.level_for(hero.xp);
+            if Upgradable::Hero20(level) == tower.get_upgrades() { continue; }
+            tower.upgrade(&defs, Upgradable::Hero20(level));
+            update_subtowers(&mut cmd, &defs, &mut game, e, children.get(e).ok(), &mut subtowers, tower.get_subtowers());
+            level_up_ew.send(HeroLevelUpEvent { id: *id, level });
+        }
+    }
+}
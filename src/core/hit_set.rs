@@ -0,0 +1,258 @@
+use bevy::utils::HashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::core::bloon::{BIDFamily, BIDTree, BloonID};
+
+/*
+    Hit set
+*/
+
+/// A set of bloons a projectile has hit. Answers "is this bloon in any recorded subtree" without scanning every hit.
+/// Every family gets its own binary trie over the `tree` bits, so lookups are O(layer) instead of O(hits).
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(from = "Vec<(BIDFamily, HitTrie)>", into = "Vec<(BIDFamily, HitTrie)>")]
+pub struct HitSet {
+    families: HashMap<BIDFamily, HitTrie>,
+}
+
+impl HitSet {
+    /// Return true iff `bloon` is a parent or a child of any recorded bloon. Same semantics as `BloonID::same_subtree_as`.
+    pub fn has_hit(&self, bloon: &BloonID)->bool {
+        if let Some(trie) = self.families.get(&bloon.family) {
+            return trie.intersects(bloon.layer, bloon.tree);
+        }
+        return false;
+    }
+    /// Record a hit bloon
+    pub fn insert(&mut self, bloon: &BloonID) {
+        self.families.entry(bloon.family).or_default().insert(bloon.layer, bloon.tree);
+    }
+    /// Forget every recorded hit (bounces, spikes resetting every tick, etc)
+    pub fn clear(&mut self) {
+        self.families.clear();
+    }
+}
+
+// saved as a list sorted by family, so that the same set always serializes the same way
+impl From<Vec<(BIDFamily, HitTrie)>> for HitSet {
+    fn from(families: Vec<(BIDFamily, HitTrie)>)->Self {
+        return HitSet { families: families.into_iter().collect() };
+    }
+}
+
+impl From<HitSet> for Vec<(BIDFamily, HitTrie)> {
+    fn from(set: HitSet)->Self {
+        let mut families: Vec<_> = set.families.into_iter().collect();
+        families.sort_unstable_by_key(|(family, _)| *family);
+        return families;
+    }
+}
+
+/// A binary trie of (layer, tree) prefixes of a single family.
+/// Node at depth `d` represents the first `d` bits of `tree`; `hit` marks a recorded bloon.
+/// Every node exists only because some recorded bloon is at or under it.
+#[derive(Clone, Serialize, Deserialize)]
+struct HitTrie {
+    nodes: Vec<HitTrieNode>,
+}
+
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+struct HitTrieNode {
+    children: [u32; 2], // 0 means no child (the root can't be anyone's child)
+    hit: bool,
+}
+
+impl Default for HitTrie {
+    fn default()->Self {
+        return HitTrie { nodes: vec![HitTrieNode::default()] };
+    }
+}
+
+impl HitTrie {
+    /// Return true iff a recorded node is an ancestor of, a descendant of, or equal to (layer, tree)
+    fn intersects(&self, layer: u8, tree: BIDTree)->bool {
+        let mut node = 0;
+        for depth in 0..layer {
+            // recorded ancestor
+            if self.nodes[node].hit { return true; }
+            let child = self.nodes[node].children[((tree >> depth) & 1) as usize];
+            if child == 0 { return false; }
+            node = child as usize;
+        }
+        // reached (layer, tree) itself; it exists, so it's either recorded or has a recorded descendant
+        return true;
+    }
+    fn insert(&mut self, layer: u8, tree: BIDTree) {
+        let mut node = 0;
+        for depth in 0..layer {
+            // already covered by a recorded ancestor
+            if self.nodes[node].hit { return; }
+            let bit = ((tree >> depth) & 1) as usize;
+            if self.nodes[node].children[bit] == 0 {
+                self.nodes.push(HitTrieNode::default());
+                self.nodes[node].children[bit] = (self.nodes.len() - 1) as u32;
+            }
+            node = self.nodes[node].children[bit] as usize;
+        }
+        self.nodes[node].hit = true;
+        // descendants are now redundant; they stay allocated but are never visited again
+        self.nodes[node].children = [0, 0];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{hint::black_box, time::Instant};
+
+    use rand::{RngCore, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    use crate::core::bloon::{Bloon, BloonTier};
+
+    use super::*;
+
+    fn bid(family: BIDFamily, layer: u8, tree: BIDTree)->BloonID {
+        return BloonID { family, layer, tree };
+    }
+
+    fn hit_set(hits: &[BloonID])->HitSet {
+        let mut set = HitSet::default();
+        for hit in hits { set.insert(hit); }
+        return set;
+    }
+
+    /// A bloon as it could appear in a real game: no `tree` bits past its layer
+    fn random_bid(rng: &mut ChaCha8Rng, families: u32, max_layer: u32)->BloonID {
+        let layer = rng.next_u32() % (max_layer + 1);
+        return bid(rng.next_u32() % families, layer as u8, rng.next_u32() & ((1 << layer) - 1));
+    }
+
+    /// Every bloon that popping a BAD all the way down spawns, the BAD included
+    fn bad_pop_tree(family: BIDFamily)->Vec<BloonID> {
+        let mut bad = Bloon::with(BloonTier::BAD, 0);
+        bad.bid = BloonID::new(family);
+        let mut bids = vec![];
+        let mut stack = vec![bad];
+        while let Some(bloon) = stack.pop() {
+            stack.extend(bloon.get_child_bloons());
+            bids.push(bloon.bid);
+        }
+        return bids;
+    }
+
+    #[test]
+    fn empty_set_has_hit_nothing() {
+        let set = HitSet::default();
+        assert!(!set.has_hit(&bid(0, 0, 0)));
+        assert!(!set.has_hit(&bid(0, 3, 0b101)));
+    }
+
+    #[test]
+    fn root_covers_its_family() {
+        let set = hit_set(&[bid(1, 0, 0)]);
+        assert!(set.has_hit(&bid(1, 0, 0)));
+        assert!(set.has_hit(&bid(1, 1, 0b1)));
+        assert!(set.has_hit(&bid(1, 5, 0b10110)));
+        assert!(!set.has_hit(&bid(0, 0, 0)));
+        assert!(!set.has_hit(&bid(2, 3, 0b101)));
+    }
+
+    #[test]
+    fn descendant_hit_covers_root() {
+        let set = hit_set(&[bid(1, 3, 0b101)]);
+        assert!(set.has_hit(&bid(1, 0, 0)));
+        assert!(!set.has_hit(&bid(0, 0, 0)));
+    }
+
+    #[test]
+    fn prefix_semantics() {
+        let set = hit_set(&[bid(0, 2, 0b01)]);
+        assert!(set.has_hit(&bid(0, 2, 0b01))); // itself
+        assert!(set.has_hit(&bid(0, 1, 0b1))); // parent
+        assert!(set.has_hit(&bid(0, 3, 0b001))); // children
+        assert!(set.has_hit(&bid(0, 3, 0b101)));
+        assert!(set.has_hit(&bid(0, 6, 0b110101))); // deep descendant
+        assert!(!set.has_hit(&bid(0, 1, 0b0))); // parent's sibling
+        assert!(!set.has_hit(&bid(0, 3, 0b010))); // not under it
+    }
+
+    #[test]
+    fn siblings_and_cousins_are_separate() {
+        let set = hit_set(&[bid(0, 2, 0b00)]);
+        assert!(!set.has_hit(&bid(0, 2, 0b10))); // sibling
+        assert!(!set.has_hit(&bid(0, 2, 0b01))); // cousins
+        assert!(!set.has_hit(&bid(0, 2, 0b11)));
+        assert!(!set.has_hit(&bid(0, 3, 0b110))); // sibling's child
+
+        // hitting both siblings still leaves the cousins alone
+        let set = hit_set(&[bid(0, 2, 0b00), bid(0, 2, 0b10)]);
+        assert!(set.has_hit(&bid(0, 1, 0b0)));
+        assert!(!set.has_hit(&bid(0, 2, 0b01)));
+        assert!(!set.has_hit(&bid(0, 2, 0b11)));
+    }
+
+    #[test]
+    fn ancestor_after_descendant() {
+        // inserting an ancestor of a recorded bloon covers the ancestor's whole subtree, not just the old path
+        let set = hit_set(&[bid(0, 3, 0b000), bid(0, 1, 0b0)]);
+        assert!(set.has_hit(&bid(0, 3, 0b110)));
+        assert!(!set.has_hit(&bid(0, 3, 0b111)));
+    }
+
+    #[test]
+    fn clear_forgets_everything() {
+        let mut set = hit_set(&[bid(0, 0, 0), bid(1, 2, 0b11)]);
+        set.clear();
+        assert!(!set.has_hit(&bid(0, 0, 0)));
+        assert!(!set.has_hit(&bid(1, 2, 0b11)));
+    }
+
+    #[test]
+    fn matches_linear_scan() {
+        let mut rng = ChaCha8Rng::seed_from_u64(7);
+        for _ in 0..200 {
+            let hits: Vec<BloonID> = (0..rng.next_u32() % 20).map(|_| random_bid(&mut rng, 3, 8)).collect();
+            let set = hit_set(&hits);
+            for _ in 0..50 {
+                let bloon = random_bid(&mut rng, 3, 8);
+                assert_eq!(set.has_hit(&bloon), hits.iter().any(|h| h.same_subtree_as(&bloon)));
+            }
+        }
+    }
+
+    #[test]
+    fn serialization_roundtrip() {
+        let hits = [bid(2, 0, 0), bid(0, 3, 0b101), bid(1, 2, 0b10)];
+        let set = hit_set(&hits);
+        let text = ron::to_string(&set).unwrap();
+        let loaded: HitSet = ron::from_str(&text).unwrap();
+        assert_eq!(text, ron::to_string(&loaded).unwrap());
+        for bloon in [bid(2, 4, 0b1111), bid(0, 1, 0b1), bid(0, 3, 0b001), bid(1, 3, 0b110), bid(1, 2, 0b11)] {
+            assert_eq!(set.has_hit(&bloon), loaded.has_hit(&bloon));
+        }
+    }
+
+    /// Compare against the linear `Vec<BloonID>` scan it replaced: `cargo test --release bench_ -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_against_linear_scan() {
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        // a high pierce projectile in a BAD rush: a few BADs popped all the way down
+        let bloons: Vec<BloonID> = (0..4).flat_map(bad_pop_tree).collect();
+        for hit_count in [10, 100, 1000, 10000] {
+            let hits: Vec<BloonID> = (0..hit_count).map(|_| bloons[rng.next_u32() as usize % bloons.len()].clone()).collect();
+            let lookups: Vec<BloonID> = (0..10000).map(|_| bloons[rng.next_u32() as usize % bloons.len()].clone()).collect();
+            let set = hit_set(&hits);
+
+            let start = Instant::now();
+            let trie_hits = lookups.iter().filter(|b| black_box(&set).has_hit(b)).count();
+            let trie_time = start.elapsed();
+            let start = Instant::now();
+            let scan_hits = lookups.iter().filter(|b| black_box(&hits).iter().any(|h| h.same_subtree_as(b))).count();
+            let scan_time = start.elapsed();
+
+            assert_eq!(trie_hits, scan_hits);
+            println!("{hit_count:>6} hits, {} lookups: trie {trie_time:?}, linear scan {scan_time:?}", lookups.len());
+        }
+    }
+}
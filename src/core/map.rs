@@ -2,35 +2,169 @@ use bevy::{math::vec2, prelude::*};
 
 #[derive(Resource, Default, Clone)]
 pub struct Map {
+    pub id: i32,
     pub path: Vec<Vec2>,
-    pub cumulative_dist: Vec<f32> // distance between nodes 0 and i
+    pub cumulative_dist: Vec<f32>, // distance between nodes 0 and i
+    pub obstacles: Vec<Obstacle>,
+    pub bounds: Rect, // the playing field; bouncing projectiles reflect off its edges
+}
+
+/// Something on the map that blocks line of sight and projectiles that aren't higher than it
+#[derive(Clone)]
+pub struct Obstacle {
+    pub polygon: Vec<Vec2>, // corners, in order; the last one connects back to the first
+    pub height: f32,
+}
+
+impl Obstacle {
+    /// First point where the segment `from`-`to` crosses an edge of this obstacle, as (fraction of the segment, edge normal facing `from`)
+    pub fn first_crossing(&self, from: Vec2, to: Vec2)->Option<(f32, Vec2)> {
+        let mut first: Option<(f32, Vec2)> = None;
+        let n = self.polygon.len();
+        for i in 0..n {
+            let (a, b) = (self.polygon[i], self.polygon[(i + 1) % n]);
+            let Some(t) = segment_crossing(from, to, a, b) else { continue; };
+            if first.is_some_and(|(best, _)| best <= t) { continue; }
+            let normal = (b - a).perp().normalize_or_zero();
+            first = Some((t, if normal.dot(to - from) > 0. { -normal } else { normal }));
+        }
+        return first;
+    }
+}
+
+/// Where along `p1`-`p2` it crosses `q1`-`q2` (0 is `p1`, 1 is `p2`), if it does
+fn segment_crossing(p1: Vec2, p2: Vec2, q1: Vec2, q2: Vec2)->Option<f32> {
+    let (r, s) = (p2 - p1, q2 - q1);
+    let denom = r.perp_dot(s);
+    if denom.abs() < f32::EPSILON { return None; } // parallel
+    let t = (q1 - p1).perp_dot(s) / denom;
+    let u = (q1 - p1).perp_dot(r) / denom;
+    if !(0. ..=1.).contains(&t) || !(0. ..=1.).contains(&u) { return None; }
+    return Some(t);
 }
 
 impl Map {
     pub fn get_map(level: i32)->Map {
         if level == 1 {
             return Map {
+                id: level,
                 path: vec![vec2(-200.,-100.), vec2(-100.,100.), vec2(100., 100.), vec2(200.,300.)],
-                cumulative_dist: vec![0., 223.60679775, 223.60679775 + 200., 223.60679775*2. + 200.]
+                cumulative_dist: vec![0., 223.60679775, 223.60679775 + 200., 223.60679775*2. + 200.],
+                obstacles: vec![
+                    Obstacle { polygon: vec![vec2(-60., -20.), vec2(-20., -20.), vec2(-20., 30.), vec2(-60., 30.)], height: 20. }, // rock
+                    Obstacle { polygon: vec![vec2(40., 40.), vec2(120., 40.), vec2(120., 50.), vec2(40., 50.)], height: 5. }, // low wall
+                ],
+                bounds: Rect::new(-640., -360., 640., 360.),
             };
         } else {
-            return Map {path: vec![], cumulative_dist: vec![]};
+            return Map {id: level, bounds: Rect::new(-640., -360., 640., 360.), ..default()};
         }
     }
     /// Get the vec2 starting location of the track
     pub fn start_pos(&self)->Vec2 {
         return self.path[0];
     }
-    /// Return a `(Vec2, usize)` tuple of position and next node of some point `dist` units along the track
+    /// Return a `(Vec2, usize)` tuple of position and next node of some point `dist` units along the track (clamped to the track)
     pub fn dist_to_pos(&self, dist: f32)->(Vec2,usize) {
-        for (i, cur_pos) in self.path.iter().enumerate() {
+        if dist <= 0. { return (self.path[0], 0); }
+        for i in 1..self.path.len() {
             if self.cumulative_dist[i] < dist { continue; }
             // found (i-1, i) nodes between which we are rn
+            let segment = self.cumulative_dist[i] - self.cumulative_dist[i-1];
+            let t = if segment > 0. { (dist - self.cumulative_dist[i-1]) / segment } else { 1. };
+            return (self.path[i-1].lerp(self.path[i], t), i);
         }
-        todo!()
+        let last = self.path.len() - 1;
+        return (self.path[last], last);
+    }
+    /// True if nothing taller than `height` is between `from` and `to`
+    pub fn line_of_sight(&self, from: Vec2, to: Vec2, height: f32)->bool {
+        return self.obstacles.iter().all(|o| o.height <= height || o.first_crossing(from, to).is_none());
+    }
+    /// First obstacle taller than `height` that the segment `from`-`to` runs into, as (where, normal of the side it hit)
+    pub fn obstacle_hit(&self, from: Vec2, to: Vec2, height: f32)->Option<(Vec2, Vec2)> {
+        return self.obstacles.iter()
+            .filter(|o| o.height > height)
+            .filter_map(|o| o.first_crossing(from, to))
+            .min_by(|(a, _), (b, _)| a.total_cmp(b))
+            .map(|(t, normal)| (from + (to - from) * t, normal));
+    }
+    /// Normal of the map edge that `to` went past, if it left the map
+    pub fn wall_hit(&self, to: Vec2)->Option<Vec2> {
+        let normal = vec2(
+            if to.x < self.bounds.min.x { 1. } else if to.x > self.bounds.max.x { -1. } else { 0. },
+            if to.y < self.bounds.min.y { 1. } else if to.y > self.bounds.max.y { -1. } else { 0. },
+        );
+        return normal.try_normalize();
     }
     /// Given a point on a map, return a point on a road that's closest to the given point.
     pub fn closest_pos(&self, around: Vec2)->Vec2 {
-        todo!()
+        let mut closest = self.path[0];
+        for segment in self.path.windows(2) {
+            let (a, b) = (segment[0], segment[1]);
+            let t = ((around - a).dot(b - a) / (b - a).length_squared()).clamp(0., 1.);
+            let pos = a + (b - a) * t;
+            if pos.distance_squared(around) < closest.distance_squared(around) { closest = pos; }
+        }
+        return closest;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crossing_segments() {
+        let t = segment_crossing(vec2(0., 0.), vec2(10., 0.), vec2(4., -5.), vec2(4., 5.));
+        assert_eq!(t, Some(0.4));
+        // would cross if either were longer
+        assert_eq!(segment_crossing(vec2(0., 0.), vec2(10., 0.), vec2(12., -5.), vec2(12., 5.)), None);
+        assert_eq!(segment_crossing(vec2(0., 0.), vec2(10., 0.), vec2(4., 1.), vec2(4., 5.)), None);
+    }
+
+    #[test]
+    fn parallel_segments_never_cross() {
+        assert_eq!(segment_crossing(vec2(0., 0.), vec2(10., 0.), vec2(0., 1.), vec2(10., 1.)), None);
+        // not even when they overlap; the edges next to it catch whatever goes through
+        assert_eq!(segment_crossing(vec2(0., 0.), vec2(10., 0.), vec2(2., 0.), vec2(8., 0.)), None);
+    }
+
+    #[test]
+    fn touching_segments_cross() {
+        // ending on the edge, starting on it, and grazing its corner
+        assert_eq!(segment_crossing(vec2(0., 0.), vec2(4., 0.), vec2(4., -5.), vec2(4., 5.)), Some(1.));
+        assert_eq!(segment_crossing(vec2(4., 0.), vec2(10., 0.), vec2(4., -5.), vec2(4., 5.)), Some(0.));
+        assert_eq!(segment_crossing(vec2(0., 0.), vec2(10., 0.), vec2(4., 0.), vec2(4., 5.)), Some(0.4));
+    }
+
+    #[test]
+    fn first_crossing_is_the_near_side() {
+        let square = Obstacle { polygon: vec![vec2(0., 0.), vec2(10., 0.), vec2(10., 10.), vec2(0., 10.)], height: 10. };
+        assert_eq!(square.first_crossing(vec2(-10., 5.), vec2(20., 5.)), Some((1. / 3., vec2(-1., 0.))));
+        assert_eq!(square.first_crossing(vec2(20., 5.), vec2(-10., 5.)), Some((1. / 3., vec2(1., 0.))));
+        assert_eq!(square.first_crossing(vec2(5., 20.), vec2(5., 12.)), None);
+    }
+
+    #[test]
+    fn only_taller_obstacles_block_sight() {
+        let map = Map::get_map(1);
+        // through the rock (height 20)
+        assert!(!map.line_of_sight(vec2(-100., 0.), vec2(0., 0.), 10.));
+        assert!(map.line_of_sight(vec2(-100., 0.), vec2(0., 0.), 20.));
+        // over the low wall (height 5)
+        assert!(map.line_of_sight(vec2(80., 0.), vec2(80., 80.), 10.));
+        assert!(!map.line_of_sight(vec2(80., 0.), vec2(80., 80.), 0.));
+        // around the rock
+        assert!(map.line_of_sight(vec2(-100., -50.), vec2(0., -50.), 0.));
+    }
+
+    #[test]
+    fn obstacle_hit_is_the_closest_one() {
+        let map = Map::get_map(1);
+        assert_eq!(map.obstacle_hit(vec2(-100., 0.), vec2(150., 45.), 0.).map(|(_, normal)| normal), Some(vec2(-1., 0.)));
+        let (at, normal) = map.obstacle_hit(vec2(80., 0.), vec2(80., 80.), 0.).unwrap();
+        assert_eq!((at, normal), (vec2(80., 40.), vec2(0., -1.)));
+        assert_eq!(map.obstacle_hit(vec2(80., 0.), vec2(80., 80.), 5.), None);
     }
 }
@@ -3,30 +3,62 @@ use bevy::prelude::*;
 pub mod bloon;
 pub mod movement;
 pub mod hitbox;
+pub mod hit_set;
 pub mod projectile;
 pub mod tower;
 pub mod map;
 pub mod event;
 pub mod attack_fn;
 pub mod upgradable;
+pub mod sim_rng;
+pub mod game;
+pub mod command;
+pub mod replay;
+pub mod state_hash;
+pub mod save;
+pub mod tower_def;
+pub mod buff;
+pub mod subtower;
+pub mod hero;
+pub mod ability;
+pub mod zombie;
 
 pub struct BTD0CorePlugin;
 
 impl Plugin for BTD0CorePlugin {
     fn build(&self, app: &mut App) {
         app.insert_resource(map::Map::get_map(1))
-        .init_resource::<bloon::OverkillLookupTable>();
+        .insert_resource(sim_rng::SimRng::from_entropy())
+        .insert_resource(tower_def::TowerDefinitions::load_embedded())
+        .insert_resource(Time::<Fixed>::from_hz(game::BASE_TICK_HZ))
+        .init_resource::<bloon::OverkillLookupTable>()
+        .init_resource::<game::SimTick>()
+        .init_resource::<game::GameState>()
+        .init_resource::<command::PendingPlayerCommands>()
+        .init_resource::<replay::ReplayRecorder>()
+        .init_resource::<state_hash::StateHash>();
 
         app.add_event::<event::GlobalDamageEvent>()
-        .add_event::<event::BloonDamageEvent>();
+        .add_event::<event::BloonDamageEvent>()
+        .add_event::<command::PlayerCommand>()
+        .add_event::<game::RoundStartEvent>()
+        .add_event::<game::RoundEndEvent>()
+        .add_event::<tower::TowerUpgradedEvent>()
+        .add_event::<tower::TowerSoldEvent>()
+        .add_event::<hero::HeroLevelUpEvent>();
 
-        app.add_systems(Startup, bloon::generate_lookup_overkill_bloon)
-        .add_systems(FixedPreUpdate, 
-            (event::global_damage_effects, projectile::damage_bloons, event::apply_bloon_damage).chain()
-        )
+        app.add_systems(Startup, (bloon::generate_lookup_overkill_bloon, sim_rng::log_seed))
+        .add_systems(FixedPreUpdate, (
+            (replay::collect_player_commands, game::apply_game_commands, tower::apply_tower_commands, subtower::apply_subtower_commands, ability::sync_abilities, ability::round_start_abilities, ability::apply_ability_commands, command::apply_debug_commands).chain(),
+            (buff::update_aura_buffs, buff::tick_buffs, subtower::inherit_parent_buffs, ability::tick_abilities).chain(),
+            (event::global_damage_effects, projectile::damage_bloons, event::apply_bloon_damage, zombie::zombie_hits).chain(),
+        ).chain())
         .add_systems(FixedUpdate, (
-            (movement::move_along_road, bloon::pop_bloons, movement::despawn_exited_road_entities).chain(),
-            (projectile::lifetime_tick, movement::move_simple).chain(),
-        ));
+            (movement::apply_blowback, bloon::tick_bloon_effects, movement::move_along_road, bloon::pop_bloons, movement::despawn_exited_road_entities).chain(),
+            (projectile::lifetime_tick, movement::update_move_targets, movement::seek_after_hit, movement::steering_move, movement::homing_move, movement::move_simple, movement::move_waypoint).chain(),
+            subtower::move_subtowers,
+        ))
+        .add_systems(FixedPostUpdate, (tower::update_effective_stats, tower::tower_attacks, game::check_round_end, buff::round_buffs, hero::hero_xp, projectile::lifetime_round, projectile::round_end_sweep).chain())
+        .add_systems(FixedLast, (state_hash::hash_simulation_state, replay::replay_checkpoint, game::advance_tick).chain());
     }
 }
\ No newline at end of file
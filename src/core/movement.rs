@@ -1,18 +1,21 @@
 use bevy::{math::{ops::hypot, vec2}, prelude::*};
+use serde::{Deserialize, Serialize};
 
-use crate::core::map::Map;
+use crate::core::{bloon::{Bloon, BloonID}, game::GameState, hitbox::HitboxSimple, map::Map, projectile::{DamageDealer, Deployed, LifetimeRound, LifetimeTick}};
 
 /*
     Movement types
 */
 
 /// A movement component that lets an entity to move along the road
-#[derive(Component, Clone)]
+#[derive(Component, Clone, Serialize, Deserialize)]
 pub struct MoveAlongRoad {
     pub target_node: usize, // next targeted node
     pub road_pos: f32, // position on the road
     pub waypoint: Vec2, // may or may not be target node's position; after reaching, incrememnt `target_node`
     pub velocity: f32,
+    #[serde(default)]
+    pub reversed: bool, // walks from the exit to the start (zombies); `target_node` counts down instead
 }
 
 impl MoveAlongRoad {
@@ -24,7 +27,7 @@ impl MoveAlongRoad {
 }
 
 /// A movement component that lets an entity to move in a straight line (add move modifier components to change direction)
-#[derive(Component, Clone)]
+#[derive(Component, Clone, Serialize, Deserialize)]
 pub struct MoveSimple {
     pub velocity: Vec2,
     pub bounce: i32, // number of bounces left
@@ -32,9 +35,11 @@ pub struct MoveSimple {
 }
 
 /// A movement component that lets an entity to rapidly move to a specified location and stay stationary after that
-#[derive(Component, Clone)]
+/// (the component is removed once it arrives, and the entity becomes `Deployed`)
+#[derive(Component, Clone, Serialize, Deserialize)]
 pub struct MoveWaypoint {
     pub waypoint: Vec2,
+    pub speed: f32,
 }
 
 /*
@@ -43,28 +48,48 @@ pub struct MoveWaypoint {
 
 /// A movement modifier component that lets an entity to steer towards the target
 /// That means turning while preserving the total velocity
-#[derive(Component, Clone)]
+#[derive(Component, Clone, Serialize, Deserialize)]
 pub struct SteeringMove {
     pub waypoint: Vec2, // where to steer towards
-    pub steer_str: f32,
+    pub steer_str: f32, // max turn per tick, in radians
     pub target: TargetMode, // used to update the `waypoint` every tick
 }
 
 /// A movement modifier component that lets an entity to home towards the target
 /// That means accelerating to the direction of target, up to a max velocity
-#[derive(Component, Clone)]
+#[derive(Component, Clone, Serialize, Deserialize)]
 pub struct HomingMove {
     pub waypoint: Vec2, // where to home towards
-    pub home_str: f32,
+    pub home_str: f32, // acceleration per tick
     pub max_velocity: f32,
     pub target: TargetMode, // used to update the `waypoint` every tick
 }
 
-/// A movement modifier component that lets an entity to sharply change own velocity after `hit_flag` has been set
-/// While default behavior is to go towards the closest bloon, it can be modified if needed
-#[derive(Component, Clone)]
+/// A movement modifier component that lets an entity to sharply change own velocity after it hits a bloon, towards the closest bloon it hasn't hit yet
+/// (Quincy's arrows, ricochets). Ricochets are bounces: each one uses up one of `MoveSimple::bounce`, the same as bouncing off a wall.
+#[derive(Component, Clone, Serialize, Deserialize)]
 pub struct SeekAfterHitMove {
-    pub hit_flag: bool,
+    pub last_hit: Option<BloonID>, // set by `damage_bloons` when it hits something and survives; it turns on the next tick
+    pub range: Option<f32>, // only turns to bloons this close; None for any distance
+    pub ricochet: bool, // turning uses up a bounce, and it can't turn without one; otherwise it turns after every hit
+    pub clear_hits: bool, // forget which bloons it has hit on every turn (except the one it's leaving), so that it can come back to them
+}
+
+/// A movement modifier component that lets a MoveSimple entity use its `bounce`s on the edges of the map too.
+/// Without it, bounces are only used on obstacles it collides with.
+#[derive(Component, Clone, Serialize, Deserialize)]
+pub struct Bounce {
+    pub clear_hits: bool, // forget which bloons it has hit on every bounce, so that it can hit them again
+}
+
+/*
+    Effects on road movement
+*/
+
+/// Pushes a bloon `distance` back along the road; put on bloons hit by a `BlowbackDealer`, and removed once applied (see `apply_blowback`)
+#[derive(Component, Clone, Serialize, Deserialize)]
+pub struct BlowbackEffect {
+    pub distance: f32,
 }
 
 /*
@@ -72,9 +97,9 @@ pub struct SeekAfterHitMove {
 */
 
 /// Targeting mode
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub enum TargetMode {
-    Entity(Entity),
+    Entity(Entity), // follow a bloon; when it's gone or has been hit, switch to the closest bloon not hit yet (or keep the last waypoint if there are none)
     Waypoint,
 }
 
@@ -82,15 +107,31 @@ pub enum TargetMode {
     Systems
 */
 
-/// Despawn MoveAlongRoad which have exited the map (gone past the last node of the map)
-pub fn despawn_exited_road_entities(mut cmd: Commands, map: Res<Map>, bloons: Query<(Entity, &MoveAlongRoad)>) {
-    for (e, re) in &bloons {
+/// Despawn MoveAlongRoad which have exited the map (gone past the last node of the map, or the first one for reversed ones)
+pub fn despawn_exited_road_entities(mut cmd: Commands, map: Res<Map>, mut game: ResMut<GameState>, bloons: Query<(Entity, &MoveAlongRoad, Has<Bloon>)>) {
+    for (e, re, is_bloon) in &bloons {
         if re.target_node == map.path.len() {
+            if is_bloon { game.lives -= 1; } // TODO: should cost the bloon's RBE
             cmd.entity(e).despawn();
         }
     }
 }
 
+/// Push bloons back along the road, no further than the start of the track. Blimps are pushed less, and bosses not at all.
+pub fn apply_blowback(mut cmd: Commands, map: Res<Map>, mut bloons: Query<(Entity, &Bloon, &BlowbackEffect, &mut MoveAlongRoad, &mut Transform)>) {
+    for (e, bloon, blowback, mut re, mut pos) in &mut bloons {
+        cmd.entity(e).remove::<BlowbackEffect>();
+        let distance = blowback.distance * bloon.tier.get_type().blowback_mult();
+        if distance <= 0. || re.target_node >= map.path.len() { continue; }
+        re.road_pos = (re.road_pos - distance).max(0.);
+        let (new_pos, next_node) = map.dist_to_pos(re.road_pos);
+        pos.translation.x = new_pos.x;
+        pos.translation.y = new_pos.y;
+        re.target_node = next_node;
+        re.waypoint = map.path[next_node];
+    }
+}
+
 /// Move MoveAlongRoad entities along the road
 pub fn move_along_road(map: Res<Map>, mut me: Query<(&mut MoveAlongRoad, &mut Transform)>) {
     for (re, pos) in &mut me {
@@ -98,12 +139,138 @@ pub fn move_along_road(map: Res<Map>, mut me: Query<(&mut MoveAlongRoad, &mut Tr
     }
 }
 
-/// Move MoveSimple entities
-pub fn move_simple(mut me: Query<(&MoveSimple, &mut Transform)>) {
-    for (p, mut pos) in &mut me {
-        // TODO: collision with obstacles (after I got obstacles in the first place)
-        pos.translation.x += p.velocity.x;
-        pos.translation.y += p.velocity.y;
+/// Move MoveSimple entities. The ones with a `collide_height` bounce off obstacles taller than that while they have bounces left, and are despawned otherwise.
+/// The ones that bounce off walls do the same at the edges of the map (but fly off it once they're out of bounces).
+pub fn move_simple(mut cmd: Commands, map: Res<Map>, mut me: Query<(Entity, &mut MoveSimple, &mut Transform, Option<&Bounce>, Option<&mut DamageDealer>)>) {
+    for (e, mut p, mut pos, bounce, dd) in &mut me {
+        let from = pos.translation.truncate();
+        let to = from + p.velocity;
+        let walls = bounce.is_some() && p.bounce > 0;
+        let hit = p.collide_height.and_then(|h| map.obstacle_hit(from, to, h))
+            .or_else(|| if walls { map.wall_hit(to).map(|normal| (from, normal)) } else { None });
+        let Some((at, normal)) = hit else {
+            pos.translation.x = to.x;
+            pos.translation.y = to.y;
+            continue;
+        };
+        if p.bounce <= 0 {
+            cmd.entity(e).despawn();
+            continue;
+        }
+        // stop just short of the obstacle and reflect; the rest of this tick's movement is lost
+        p.bounce -= 1;
+        p.velocity = p.velocity.reflect(normal);
+        let stop = at + normal * 0.01;
+        pos.translation.x = stop.x;
+        pos.translation.y = stop.y;
+        if let (Some(bounce), Some(mut dd)) = (bounce, dd) {
+            if bounce.clear_hits { dd.hit_bloons.clear(); }
+        }
+    }
+}
+
+/// Move MoveWaypoint entities towards their waypoint. Once there, they stop and become `Deployed`.
+/// A damage dealer landing on a deployed one with the same damage merges into it (see `merge_into_pile`), so spikes pile up instead of overlapping.
+pub fn move_waypoint(
+    mut cmd: Commands,
+    mut me: Query<(Entity, &MoveWaypoint, &mut Transform, Option<(&mut DamageDealer, &HitboxSimple)>, Option<&mut LifetimeTick>, Option<&mut LifetimeRound>), Without<Deployed>>,
+    mut piles: Query<(Entity, &mut DamageDealer, &HitboxSimple, &Transform, Option<&mut LifetimeTick>, Option<&mut LifetimeRound>), With<Deployed>>,
+) {
+    let mut landed = vec![];
+    for (e, mw, mut pos, ..) in &mut me {
+        let to_target = mw.waypoint - pos.translation.truncate();
+        if to_target.length() > mw.speed {
+            pos.translation += (to_target.normalize() * mw.speed).extend(0.);
+            continue;
+        }
+        pos.translation = mw.waypoint.extend(pos.translation.z);
+        cmd.entity(e).remove::<MoveWaypoint>();
+        landed.push(e);
+    }
+
+    // entities landing this tick aren't `Deployed` yet, so they can only merge with each other through `me`
+    let mut new_piles: Vec<Entity> = vec![];
+    for e in landed {
+        let Ok((_, mw, _, Some((dd, _)), life, rounds)) = me.get(e) else { cmd.entity(e).insert(Deployed); continue; };
+        let (spot, damage, cannot_pop) = (mw.waypoint, dd.damage, dd.cannot_pop_modifiers);
+        let landing = (dd.pierce, life.map(|l| l.lifetime), rounds.map(|r| r.lifetime_rounds));
+        let same_pile = |pile: &DamageDealer, hb: &HitboxSimple, pos: &Transform| {
+            pile.damage == damage && pile.cannot_pop_modifiers == cannot_pop && pos.translation.truncate().distance(spot) <= hb.radius
+        };
+        if let Some((pe, pile, _, _, life, rounds)) = piles.iter_mut().find(|(_, pile, hb, pos, ..)| same_pile(pile, hb, pos)) {
+            merge_into_pile(&mut cmd, pe, pile.into_inner(), life, rounds, landing);
+            cmd.entity(e).despawn();
+        } else if let Some(pile) = new_piles.iter().copied().find(|p| me.get(*p).is_ok_and(|(_, _, pos, dd, ..)| dd.is_some_and(|(pile, hb)| same_pile(pile, hb, pos)))) {
+            if let Ok((pe, _, _, Some((pile, _)), life, rounds)) = me.get_mut(pile) {
+                merge_into_pile(&mut cmd, pe, pile.into_inner(), life, rounds, landing);
+            }
+            cmd.entity(e).despawn();
+        } else {
+            cmd.entity(e).insert(Deployed);
+            new_piles.push(e);
+        }
+    }
+}
+
+/// Update the waypoints of steering and homing entities that follow a bloon
+pub fn update_move_targets(bloons: Query<(Entity, &Bloon, &Transform)>, mut steering: Query<(&mut SteeringMove, &Transform, Option<&DamageDealer>)>, mut homing: Query<(&mut HomingMove, &Transform, Option<&DamageDealer>)>) {
+    for (mut st, pos, dd) in &mut steering {
+        let st = &mut *st;
+        follow_target(&mut st.target, &mut st.waypoint, pos.translation.truncate(), dd, &bloons);
+    }
+    for (mut ho, pos, dd) in &mut homing {
+        let ho = &mut *ho;
+        follow_target(&mut ho.target, &mut ho.waypoint, pos.translation.truncate(), dd, &bloons);
+    }
+}
+
+/// Redirect entities that just hit a bloon towards the closest bloon (in range) they haven't hit yet, keeping their speed; ricochets use up a bounce.
+/// Steering and homing ones follow the new bloon from then on, instead of turning back to the old one.
+pub fn seek_after_hit(
+    bloons: Query<(Entity, &Bloon, &Transform)>,
+    mut me: Query<(&mut SeekAfterHitMove, &mut MoveSimple, &Transform, Option<&mut DamageDealer>, Option<&mut SteeringMove>, Option<&mut HomingMove>)>,
+) {
+    for (mut seek, mut p, pos, dd, steering, homing) in &mut me {
+        let Some(last_hit) = seek.last_hit.take() else { continue; };
+        if seek.ricochet && p.bounce <= 0 { continue; }
+        let pos = pos.translation.truncate();
+        let Some((e, target)) = closest_bloon(pos, dd.as_deref(), &bloons) else { continue; };
+        if seek.range.is_some_and(|range| target.distance(pos) > range) { continue; }
+        if let Some(dir) = (target - pos).try_normalize() {
+            p.velocity = dir * p.velocity.length();
+        }
+        if seek.ricochet { p.bounce -= 1; }
+        if seek.clear_hits {
+            // keep the one it's leaving, or it would hit it again on the way out
+            if let Some(mut dd) = dd {
+                dd.hit_bloons.clear();
+                dd.hit_bloons.insert(&last_hit);
+            }
+        }
+        if let Some(mut st) = steering {
+            (st.target, st.waypoint) = (TargetMode::Entity(e), target);
+        }
+        if let Some(mut ho) = homing {
+            (ho.target, ho.waypoint) = (TargetMode::Entity(e), target);
+        }
+    }
+}
+
+/// Turn SteeringMove entities towards their waypoint, at most `steer_str` per tick; the speed doesn't change
+pub fn steering_move(mut me: Query<(&SteeringMove, &mut MoveSimple, &Transform)>) {
+    for (st, mut p, pos) in &mut me {
+        let to_target = st.waypoint - pos.translation.truncate();
+        if to_target.length_squared() < f32::EPSILON || p.velocity.length_squared() < f32::EPSILON { continue; }
+        let turn = p.velocity.angle_to(to_target).clamp(-st.steer_str, st.steer_str);
+        p.velocity = Vec2::from_angle(turn).rotate(p.velocity);
+    }
+}
+
+/// Accelerate HomingMove entities towards their waypoint, up to `max_velocity`
+pub fn homing_move(mut me: Query<(&HomingMove, &mut MoveSimple, &Transform)>) {
+    for (ho, mut p, pos) in &mut me {
+        let Some(dir) = (ho.waypoint - pos.translation.truncate()).try_normalize() else { continue; };
+        p.velocity = (p.velocity + dir * ho.home_str).clamp_length_max(ho.max_velocity);
     }
 }
 
@@ -111,6 +278,49 @@ pub fn move_simple(mut me: Query<(&MoveSimple, &mut Transform)>) {
     Helper Functions
 */
 
+/// Closest bloon to `pos` that `dd` hasn't hit yet (any bloon if there's no `dd`). Ties go to the lowest BloonID, so that it's deterministic.
+fn closest_bloon(pos: Vec2, dd: Option<&DamageDealer>, bloons: &Query<(Entity, &Bloon, &Transform)>)->Option<(Entity, Vec2)> {
+    return bloons.iter()
+        .filter(|(_, bloon, _)| dd.is_none_or(|dd| !dd.has_hit(&bloon.bid)))
+        .min_by(|(_, a, apos), (_, b, bpos)| {
+            apos.translation.truncate().distance(pos).total_cmp(&bpos.translation.truncate().distance(pos))
+            .then((a.bid.family, a.bid.layer, a.bid.tree).cmp(&(b.bid.family, b.bid.layer, b.bid.tree)))
+        })
+        .map(|(e, _, bpos)| (e, bpos.translation.truncate()));
+}
+
+/// Merge a landed damage dealer (its pierce, tick and round lifetimes) into a pile. The pierce adds up and the pile lasts as long as the longer-lived of the two
+/// (no lifetime is forever); the new pierce hasn't hit anything yet, so the pile forgets what it has hit.
+fn merge_into_pile(cmd: &mut Commands, pile_e: Entity, pile: &mut DamageDealer, life: Option<Mut<LifetimeTick>>, rounds: Option<Mut<LifetimeRound>>, (pierce, new_life, new_rounds): (u32, Option<i32>, Option<i32>)) {
+    pile.pierce += pierce;
+    pile.hit_bloons.clear();
+    match (life, new_life) {
+        (Some(mut life), Some(new_life)) => life.lifetime = life.lifetime.max(new_life),
+        (Some(_), None) => { cmd.entity(pile_e).remove::<LifetimeTick>(); },
+        (None, _) => {},
+    }
+    match (rounds, new_rounds) {
+        (Some(mut rounds), Some(new_rounds)) => rounds.lifetime_rounds = rounds.lifetime_rounds.max(new_rounds),
+        (Some(_), None) => { cmd.entity(pile_e).remove::<LifetimeRound>(); },
+        (None, _) => {},
+    }
+}
+
+/// Move `waypoint` to where the followed bloon is now; if it's gone (or already hit), pick a new one
+fn follow_target(target: &mut TargetMode, waypoint: &mut Vec2, pos: Vec2, dd: Option<&DamageDealer>, bloons: &Query<(Entity, &Bloon, &Transform)>) {
+    let TargetMode::Entity(e) = *target else { return; };
+    if let Ok((_, bloon, bpos)) = bloons.get(e) {
+        if dd.is_none_or(|dd| !dd.has_hit(&bloon.bid)) {
+            *waypoint = bpos.translation.truncate();
+            return;
+        }
+    }
+    match closest_bloon(pos, dd, bloons) {
+        Some((e, bpos)) => { *target = TargetMode::Entity(e); *waypoint = bpos; },
+        None => *target = TargetMode::Waypoint,
+    }
+}
+
 /// Move a given MoveAlongRoad along the road with the given step size (that should depend on its speed)
 pub fn advance_move_along_road(step: f32, map: &Map, re: &mut MoveAlongRoad, pos: &mut Transform) {
     let dx = re.waypoint.x - pos.translation.x; // x difference between a waypoint and a current position
@@ -121,19 +331,355 @@ pub fn advance_move_along_road(step: f32, map: &Map, re: &mut MoveAlongRoad, pos
         // Move to the node and advance the node index
         pos.translation.x = re.waypoint.x;
         pos.translation.y = re.waypoint.y;
+        if re.reversed {
+            re.road_pos = map.cumulative_dist[re.target_node];
+            if re.target_node == 0 {
+                // got to the start; the same "exited" marker as the forward direction
+                re.target_node = map.path.len();
+                re.waypoint = vec2(f32::MAX,f32::MAX);
+                return;
+            }
+            re.target_node -= 1;
+            re.waypoint = map.path[re.target_node];
+            advance_move_along_road(step-total_dist, map, re, pos);
+            return;
+        }
+        // `road_pos` is the distance of the node just reached, so it stays in line with `Map::dist_to_pos`
+        re.road_pos = map.cumulative_dist[re.target_node];
         re.target_node += 1;
         if re.target_node < map.path.len() {
             re.waypoint = map.path[re.target_node];
-            re.road_pos = map.cumulative_dist[re.target_node];
         } else {
             // maybe do something else; essentially make it do something for a tick until it's despawned
             re.waypoint = vec2(f32::MAX,f32::MAX);
-            re.road_pos = 0.;
         }
         advance_move_along_road(step-total_dist, map, re, pos);
     } else {
         pos.translation.x += dx * step / total_dist;
         pos.translation.y += dy * step / total_dist;
-        re.road_pos += step;
+        re.road_pos += if re.reversed { -step } else { step };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use crate::core::{bloon::{BloonTier, BloonType}, hit_set::HitSet};
+
+    use super::*;
+
+    fn spawn_bloon(world: &mut World, family: u32, pos: Vec2)->BloonID {
+        let mut bloon = Bloon::with(BloonTier::Red, 0);
+        bloon.bid = BloonID::new(family);
+        let bid = bloon.bid.clone();
+        world.spawn((bloon, Transform::from_translation(pos.extend(0.))));
+        return bid;
+    }
+
+    /// A projectile at the origin flying right, that just hit `hit`
+    /// With `bounces`, it ricochets that many times; without, it turns after every hit
+    fn spawn_seeker(world: &mut World, hit: &BloonID, range: Option<f32>, bounces: Option<i32>)->Entity {
+        let mut hit_bloons = HitSet::default();
+        hit_bloons.insert(hit);
+        return world.spawn((
+            SeekAfterHitMove { last_hit: Some(hit.clone()), range, ricochet: bounces.is_some(), clear_hits: false },
+            MoveSimple { velocity: vec2(5., 0.), bounce: bounces.unwrap_or(0), collide_height: None },
+            DamageDealer { damage: 1, pierce: 5, hit_bloons, cannot_pop_modifiers: 0, cannot_target_modifiers: 0, seq: 1 },
+            Transform::default(),
+        )).id();
+    }
+
+    /// A projectile at `pos` on map 1 flying right, that collides with obstacles taller than `height`
+    fn spawn_flyer(world: &mut World, pos: Vec2, height: Option<f32>, bounce: i32)->Entity {
+        return world.spawn((MoveSimple { velocity: vec2(30., 0.), bounce, collide_height: height }, Transform::from_translation(pos.extend(0.)))).id();
+    }
+
+    fn pos_of(world: &World, e: Entity)->Vec2 {
+        return world.get::<Transform>(e).unwrap().translation.truncate();
+    }
+
+    #[test]
+    fn bounces_off_taller_obstacles() {
+        let mut world = World::new();
+        world.insert_resource(Map::get_map(1));
+        // the rock (height 20) starts at x = -60
+        let e = spawn_flyer(&mut world, vec2(-80., 0.), Some(10.), 1);
+        world.run_system_once(move_simple).unwrap();
+        let p = world.get::<MoveSimple>(e).unwrap();
+        assert_eq!((p.velocity, p.bounce), (vec2(-30., 0.), 0));
+        assert!((pos_of(&world, e) - vec2(-60.01, 0.)).length() < 1e-3);
+        world.run_system_once(move_simple).unwrap();
+        assert!((pos_of(&world, e) - vec2(-90.01, 0.)).length() < 1e-3);
+    }
+
+    #[test]
+    fn flies_over_lower_obstacles() {
+        let mut world = World::new();
+        world.insert_resource(Map::get_map(1));
+        let over = spawn_flyer(&mut world, vec2(-80., 0.), Some(20.), 0);
+        let ignores = spawn_flyer(&mut world, vec2(-80., 5.), None, 0);
+        world.run_system_once(move_simple).unwrap();
+        assert_eq!(pos_of(&world, over), vec2(-50., 0.));
+        assert_eq!(pos_of(&world, ignores), vec2(-50., 5.));
+    }
+
+    #[test]
+    fn despawns_on_obstacles_without_bounces() {
+        let mut world = World::new();
+        world.insert_resource(Map::get_map(1));
+        let e = spawn_flyer(&mut world, vec2(-80., 0.), Some(10.), 0);
+        world.run_system_once(move_simple).unwrap();
+        assert!(world.get_entity(e).is_err());
+    }
+
+    #[test]
+    fn seek_turns_to_closest_bloon_not_hit() {
+        let mut world = World::new();
+        let hit = spawn_bloon(&mut world, 0, vec2(2., 0.));
+        spawn_bloon(&mut world, 1, vec2(0., 50.));
+        spawn_bloon(&mut world, 2, vec2(0., -80.));
+        let e = spawn_seeker(&mut world, &hit, Some(100.), Some(1));
+        world.run_system_once(seek_after_hit).unwrap();
+        assert_eq!(world.get::<MoveSimple>(e).unwrap().velocity, vec2(0., 5.));
+        assert!(world.get::<SeekAfterHitMove>(e).unwrap().last_hit.is_none());
+        assert_eq!(world.get::<MoveSimple>(e).unwrap().bounce, 0);
+    }
+
+    #[test]
+    fn seek_stays_in_range() {
+        let mut world = World::new();
+        let hit = spawn_bloon(&mut world, 0, vec2(2., 0.));
+        spawn_bloon(&mut world, 1, vec2(0., 150.));
+        let e = spawn_seeker(&mut world, &hit, Some(100.), None);
+        world.run_system_once(seek_after_hit).unwrap();
+        assert_eq!(world.get::<MoveSimple>(e).unwrap().velocity, vec2(5., 0.));
+    }
+
+    #[test]
+    fn ricochet_runs_out_of_bounces() {
+        let mut world = World::new();
+        let hit = spawn_bloon(&mut world, 0, vec2(2., 0.));
+        spawn_bloon(&mut world, 1, vec2(0., 50.));
+        let e = spawn_seeker(&mut world, &hit, None, Some(0));
+        world.run_system_once(seek_after_hit).unwrap();
+        assert_eq!(world.get::<MoveSimple>(e).unwrap().velocity, vec2(5., 0.));
+    }
+
+    #[test]
+    fn seek_without_ricochet_keeps_bounces() {
+        let mut world = World::new();
+        let hit = spawn_bloon(&mut world, 0, vec2(2., 0.));
+        spawn_bloon(&mut world, 1, vec2(0., 50.));
+        let e = spawn_seeker(&mut world, &hit, None, None);
+        world.get_mut::<MoveSimple>(e).unwrap().bounce = 2;
+        world.run_system_once(seek_after_hit).unwrap();
+        let p = world.get::<MoveSimple>(e).unwrap();
+        assert_eq!((p.velocity, p.bounce), (vec2(0., 5.), 2));
+    }
+
+    #[test]
+    fn wall_hit_normals() {
+        let map = Map::get_map(1);
+        assert_eq!(map.wall_hit(vec2(0., 0.)), None);
+        assert_eq!(map.wall_hit(vec2(700., 0.)), Some(vec2(-1., 0.)));
+        assert_eq!(map.wall_hit(vec2(0., -400.)), Some(vec2(0., 1.)));
+        // out past a corner: straight back
+        assert_eq!(map.wall_hit(vec2(-700., 400.)), Some(vec2(1., -1.).normalize()));
+    }
+
+    /// A `Bounce` projectile about to fly off the right edge of map 1, that has hit bloon family 0
+    fn spawn_wall_bouncer(world: &mut World, bounce: i32, clear_hits: bool)->Entity {
+        let mut hit_bloons = HitSet::default();
+        hit_bloons.insert(&BloonID::new(0));
+        return world.spawn((
+            MoveSimple { velocity: vec2(30., 0.), bounce, collide_height: None },
+            Bounce { clear_hits },
+            DamageDealer { damage: 1, pierce: 5, hit_bloons, cannot_pop_modifiers: 0, cannot_target_modifiers: 0, seq: 1 },
+            Transform::from_xyz(620., 0., 0.),
+        )).id();
+    }
+
+    #[test]
+    fn bounces_off_walls() {
+        let mut world = World::new();
+        world.insert_resource(Map::get_map(1));
+        let keeps = spawn_wall_bouncer(&mut world, 1, false);
+        let clears = spawn_wall_bouncer(&mut world, 1, true);
+        world.run_system_once(move_simple).unwrap();
+        for e in [keeps, clears] {
+            let p = world.get::<MoveSimple>(e).unwrap();
+            assert_eq!((p.velocity, p.bounce), (vec2(-30., 0.), 0));
+            assert!((pos_of(&world, e) - vec2(619.99, 0.)).length() < 1e-3);
+        }
+        assert!(world.get::<DamageDealer>(keeps).unwrap().has_hit(&BloonID::new(0)));
+        assert!(!world.get::<DamageDealer>(clears).unwrap().has_hit(&BloonID::new(0)));
+    }
+
+    #[test]
+    fn flies_off_the_map_without_bounces() {
+        let mut world = World::new();
+        world.insert_resource(Map::get_map(1));
+        let e = spawn_wall_bouncer(&mut world, 0, false);
+        // walls don't despawn it like obstacles do; leaving the map is up to its lifetime
+        world.run_system_once(move_simple).unwrap();
+        assert_eq!(pos_of(&world, e), vec2(650., 0.));
+        let no_bounce = world.spawn((MoveSimple { velocity: vec2(30., 0.), bounce: 3, collide_height: None }, Transform::from_xyz(620., 0., 0.))).id();
+        world.run_system_once(move_simple).unwrap();
+        assert_eq!(pos_of(&world, no_bounce), vec2(650., 0.));
     }
-}
\ No newline at end of file
+
+    fn steerer(waypoint: Vec2, target: TargetMode)->(SteeringMove, MoveSimple, Transform) {
+        return (SteeringMove { waypoint, steer_str: 0.1, target }, MoveSimple { velocity: vec2(5., 0.), bounce: 0, collide_height: None }, Transform::default());
+    }
+
+    #[test]
+    fn steering_turns_at_most_steer_str() {
+        let mut world = World::new();
+        let far = world.spawn(steerer(vec2(0., 100.), TargetMode::Waypoint)).id();
+        let near = world.spawn(steerer(Vec2::from_angle(0.05) * 100., TargetMode::Waypoint)).id();
+        world.run_system_once(steering_move).unwrap();
+        let v = world.get::<MoveSimple>(far).unwrap().velocity;
+        assert!((v.to_angle() - 0.1).abs() < 1e-5 && (v.length() - 5.).abs() < 1e-5);
+        let v = world.get::<MoveSimple>(near).unwrap().velocity;
+        assert!((v.to_angle() - 0.05).abs() < 1e-5 && (v.length() - 5.).abs() < 1e-5);
+    }
+
+    #[test]
+    fn homing_accelerates_up_to_max_velocity() {
+        let mut world = World::new();
+        let e = world.spawn((
+            HomingMove { waypoint: vec2(0., 100.), home_str: 1., max_velocity: 2.5, target: TargetMode::Waypoint },
+            MoveSimple { velocity: vec2(1., 0.), bounce: 0, collide_height: None },
+            Transform::default(),
+        )).id();
+        world.run_system_once(homing_move).unwrap();
+        assert_eq!(world.get::<MoveSimple>(e).unwrap().velocity, vec2(1., 1.));
+        world.run_system_once(homing_move).unwrap();
+        assert_eq!(world.get::<MoveSimple>(e).unwrap().velocity, vec2(1., 2.));
+        world.run_system_once(homing_move).unwrap();
+        let v = world.get::<MoveSimple>(e).unwrap().velocity;
+        assert!((v.length() - 2.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn follows_its_bloon() {
+        let mut world = World::new();
+        spawn_bloon(&mut world, 0, vec2(0., 40.));
+        let target = world.spawn((Bloon::with(BloonTier::Red, 0), Transform::from_xyz(50., 50., 0.))).id();
+        let e = world.spawn(steerer(Vec2::ZERO, TargetMode::Entity(target))).id();
+        world.run_system_once(update_move_targets).unwrap();
+        assert_eq!(world.get::<SteeringMove>(e).unwrap().waypoint, vec2(50., 50.));
+    }
+
+    #[test]
+    fn follows_another_bloon_once_its_gone() {
+        let mut world = World::new();
+        let gone = world.spawn((Bloon::with(BloonTier::Red, 0), Transform::default())).id();
+        world.despawn(gone);
+        spawn_bloon(&mut world, 1, vec2(0., 80.));
+        spawn_bloon(&mut world, 2, vec2(30., 0.));
+        let e = world.spawn(steerer(Vec2::ZERO, TargetMode::Entity(gone))).id();
+        world.run_system_once(update_move_targets).unwrap();
+        let st = world.get::<SteeringMove>(e).unwrap();
+        assert_eq!(st.waypoint, vec2(30., 0.));
+        assert!(matches!(st.target, TargetMode::Entity(t) if t != gone));
+    }
+
+    #[test]
+    fn follows_another_bloon_once_its_hit() {
+        let mut world = World::new();
+        let hit = spawn_bloon(&mut world, 0, vec2(10., 0.));
+        let target = world.query::<(Entity, &Bloon)>().iter(&world).find(|(_, b)| b.bid == hit).unwrap().0;
+        spawn_bloon(&mut world, 1, vec2(0., 80.));
+        let mut hit_bloons = HitSet::default();
+        hit_bloons.insert(&hit);
+        let e = world.spawn((steerer(Vec2::ZERO, TargetMode::Entity(target)), DamageDealer { damage: 1, pierce: 5, hit_bloons, cannot_pop_modifiers: 0, cannot_target_modifiers: 0, seq: 1 })).id();
+        world.run_system_once(update_move_targets).unwrap();
+        assert_eq!(world.get::<SteeringMove>(e).unwrap().waypoint, vec2(0., 80.));
+    }
+
+    #[test]
+    fn keeps_the_waypoint_without_bloons() {
+        let mut world = World::new();
+        let gone = world.spawn((Bloon::with(BloonTier::Red, 0), Transform::default())).id();
+        world.despawn(gone);
+        let e = world.spawn((
+            HomingMove { waypoint: vec2(7., 7.), home_str: 1., max_velocity: 2., target: TargetMode::Entity(gone) },
+            MoveSimple { velocity: Vec2::ZERO, bounce: 0, collide_height: None },
+            Transform::default(),
+        )).id();
+        world.run_system_once(update_move_targets).unwrap();
+        let ho = world.get::<HomingMove>(e).unwrap();
+        assert_eq!(ho.waypoint, vec2(7., 7.));
+        assert!(matches!(ho.target, TargetMode::Waypoint));
+    }
+
+    fn road_bloon(tier: BloonTier, map: &Map, road_pos: f32)->(Bloon, MoveAlongRoad, Transform) {
+        let (pos, next) = map.dist_to_pos(road_pos);
+        let re = MoveAlongRoad { target_node: next, road_pos, waypoint: map.path[next], velocity: 1., reversed: false };
+        return (Bloon::with(tier, 0), re, Transform::from_translation(pos.extend(0.)));
+    }
+
+    /// Push a bloon at `road_pos` back by `distance`, and return where it ended up
+    fn blow_back(tier: BloonTier, road_pos: f32, distance: f32)->(MoveAlongRoad, Vec2) {
+        let mut world = World::new();
+        let map = Map::get_map(1);
+        let e = world.spawn((road_bloon(tier, &map, road_pos), BlowbackEffect { distance })).id();
+        world.insert_resource(map);
+        world.run_system_once(apply_blowback).unwrap();
+        assert!(world.get::<BlowbackEffect>(e).is_none());
+        return (world.get::<MoveAlongRoad>(e).unwrap().clone(), world.get::<Transform>(e).unwrap().translation.truncate());
+    }
+
+    #[test]
+    fn blowback_stops_at_track_start() {
+        let map = Map::get_map(1);
+        let (re, pos) = blow_back(BloonTier::Red, 50., 100.);
+        assert_eq!(re.road_pos, 0.);
+        assert_eq!(re.target_node, 0);
+        assert_eq!(re.waypoint, map.path[0]);
+        assert_eq!(pos, map.path[0]);
+    }
+
+    #[test]
+    fn blowback_across_a_node() {
+        let map = Map::get_map(1);
+        // from the third segment back into the second
+        let (re, pos) = blow_back(BloonTier::Red, 500., 150.);
+        assert_eq!(re.road_pos, 350.);
+        assert_eq!(re.target_node, 2);
+        assert_eq!(re.waypoint, map.path[2]);
+        assert!(pos.distance(map.dist_to_pos(350.).0) < 1e-3);
+    }
+
+    #[test]
+    fn blowback_multipliers() {
+        assert_eq!(blow_back(BloonTier::Red, 300., 40.).0.road_pos, 260.);
+        assert_eq!(blow_back(BloonTier::MOAB, 300., 40.).0.road_pos, 290.);
+        assert_eq!(BloonType::Boss.blowback_mult(), 0.);
+    }
+
+    #[test]
+    fn road_pos_matches_position() {
+        let map = Map::get_map(1);
+        let (_, mut re, mut pos) = road_bloon(BloonTier::Red, &map, 0.);
+        let mut walked = 0.;
+        loop {
+            advance_move_along_road(7., &map, &mut re, &mut pos);
+            walked += 7.;
+            if re.target_node >= map.path.len() { break; }
+            assert!((re.road_pos - walked).abs() < 1e-2, "road_pos {} after walking {}", re.road_pos, walked);
+            assert!(map.dist_to_pos(re.road_pos).0.distance(pos.translation.truncate()) < 1e-2);
+        }
+
+        // and after a blowback
+        let (mut re, pos) = blow_back(BloonTier::Red, 430., 100.);
+        let mut pos = Transform::from_translation(pos.extend(0.));
+        for _ in 0..20 {
+            advance_move_along_road(7., &map, &mut re, &mut pos);
+            assert_eq!(map.dist_to_pos(re.road_pos).1, re.target_node);
+            assert!(map.dist_to_pos(re.road_pos).0.distance(pos.translation.truncate()) < 1e-2);
+        }
+    }
+}
@@ -1,45 +1,76 @@
-use std::sync::Mutex;
+use bevy::{ecs::{component::ComponentId, world::DeferredWorld}, math::{ops::hypot, vec2, vec3}, prelude::*, tasks::ComputeTaskPool, utils::HashMap};
+use serde::{Deserialize, Serialize};
 
-use bevy::{math::{ops::hypot, vec2}, prelude::*};
-
-use crate::{core::{bloon::{Bloon, BloonID, BloonModifier}, event::BloonDamageEvent, hitbox::HitboxSimple}};
+use crate::{core::{bloon::{Bloon, BloonEffect, BloonID, BloonModifier}, event::BloonDamageEvent, game::{GameState, RoundEndEvent}, hit_set::HitSet, hitbox::HitboxSimple, movement::{BlowbackEffect, MoveAlongRoad, MoveSimple, SeekAfterHitMove}}};
 
 /*
     Lifetime
 */
 
 /// A component which will decrement own lifetime every tick; despawn this entity once lifetime ran out
-#[derive(Component, Clone)]
+#[derive(Component, Clone, Serialize, Deserialize)]
 pub struct LifetimeTick {
     pub lifetime: i32,
 }
 
 /// A component which will decrement own lifetime every round; despawn this entity once lifetime ran out
-#[derive(Component, Clone)]
+#[derive(Component, Clone, Serialize, Deserialize)]
 pub struct LifetimeRound {
     pub lifetime_rounds: i32,
 }
 
+/// Something lying on the map that gives cash when collected (bananas). Leftovers are collected at the end of the round.
+#[derive(Component, Clone, Serialize, Deserialize)]
+pub struct Collectible {
+    pub cash: i64,
+}
+
+/// A projectile that flew to its spot with `MoveWaypoint` and stays there (spikes, mines)
+#[derive(Component, Clone, Serialize, Deserialize)]
+pub struct Deployed;
+
 /*
     Damage dealing
 */
 
 /// A component that allows an entity to deal damage to bloons
-#[derive(Component, Clone)]
+#[derive(Component, Clone, Serialize, Deserialize)]
+#[component(on_add = number_damage_dealer)]
 pub struct DamageDealer {
     pub damage: i32,
     pub pierce: u32,
-    pub hit_bloons: Vec<BloonID>,
+    pub hit_bloons: HitSet,
     pub cannot_pop_modifiers: BloonModifier,
     pub cannot_target_modifiers: BloonModifier,
+    #[serde(default)]
+    pub seq: u64, // spawn order, given out when it's added (leave at 0); hits are resolved in this order
+}
+
+/// Number damage dealers in the order they are spawned. Commands are applied in the order they were queued, so this is the same every run,
+/// and it's saved, so it survives loading. Damage dealers that come with a number (loaded ones) keep it.
+fn number_damage_dealer(mut world: DeferredWorld, e: Entity, _: ComponentId) {
+    if world.get::<DamageDealer>(e).is_none_or(|dd| dd.seq != 0) { return; }
+    let Some(mut game) = world.get_resource_mut::<GameState>() else { return; };
+    game.next_projectile_seq += 1;
+    let seq = game.next_projectile_seq;
+    if let Some(mut dd) = world.get_mut::<DamageDealer>(e) { dd.seq = seq; }
+}
+
+/// Lets a damage dealer push the bloons it hits back along the road
+#[derive(Component, Clone, Serialize, Deserialize)]
+pub struct BlowbackDealer {
+    pub distance: f32,
+}
+
+/// Lets a damage dealer put status effects (freeze, glue) on the bloons it damages
+#[derive(Component, Clone, Serialize, Deserialize)]
+pub struct EffectDealer {
+    pub effects: Vec<BloonEffect>,
 }
 
 impl DamageDealer {
     pub fn has_hit(&self, bloon: &BloonID)->bool {
-        for i in &self.hit_bloons {
-            if i.same_subtree_as(bloon) { return true; }
-        }
-        return false;
+        return self.hit_bloons.has_hit(bloon);
     }
 }
 
@@ -47,31 +78,102 @@ impl DamageDealer {
     Systems
 */
 
+/// A bloon that a projectile overlaps this tick and hasn't hit before
+struct HitCandidate {
+    bloon: Entity,
+    bid: BloonID,
+    hp: i32,
+    modifiers: BloonModifier,
+    road_pos: f32,
+}
+
+/// Find every bloon the given projectile overlaps and hasn't hit yet, in a stable order (furthest along the road first, then by id)
+fn find_hit_candidates(p: &DamageDealer, phb: &HitboxSimple, ppos: &Transform, bloons: &Query<(Entity, &Bloon, &MoveAlongRoad, &HitboxSimple, &Transform)>)->Vec<HitCandidate> {
+    let mut candidates = vec![];
+    for (be, bloon, re, bhb, bpos) in bloons {
+        let critical_dist = phb.radius + bhb.radius;
+        // if AABB intersect, and actually intersect, and hasn't hit before
+        if (bpos.translation.x - ppos.translation.x).abs() < critical_dist && (bpos.translation.y - ppos.translation.y).abs() < critical_dist &&
+        hypot(ppos.translation.x - bpos.translation.x, ppos.translation.y - bpos.translation.y) < critical_dist &&
+        !p.has_hit(&bloon.bid) {
+            candidates.push(HitCandidate { bloon: be, bid: bloon.bid.clone(), hp: bloon.hp, modifiers: bloon.modifiers, road_pos: re.road_pos });
+        }
+    }
+    candidates.sort_unstable_by(|a, b| {
+        b.road_pos.total_cmp(&a.road_pos).then((a.bid.family, a.bid.layer, a.bid.tree).cmp(&(b.bid.family, b.bid.layer, b.bid.tree)))
+    });
+    return candidates;
+}
+
 /// Test if projectiles collide with bloons. If yes, send a damage taken event.
 /// Ok this parallel shit rocks. Like, it went from turning my game into a slideshow to tanking to stable 20 fps... with 2x bloons on screen. Crazy how much difference parallel makes.
-pub fn damage_bloons(cmd: ParallelCommands, damage_ew: EventWriter<BloonDamageEvent>, bloons: Query<(Entity, &Bloon, &HitboxSimple, &Transform)>, mut p: Query<(Entity, &mut DamageDealer, &HitboxSimple, &Transform)>) {
-    let mutex = Mutex::from(damage_ew);
-    p.par_iter_mut().for_each(|(pe, mut p, phb, ppos)| {
-        let mut damage_events = vec![];
-        for (be, bloon, bhb, bpos) in &bloons {
-            let critical_dist = phb.radius + bhb.radius;
-            // if AABB intersect, and actually intersect, and hasn't hit before
-            if (bpos.translation.x - ppos.translation.x).abs() < critical_dist && (bpos.translation.y - ppos.translation.y).abs() < critical_dist &&
-            hypot(ppos.translation.x - bpos.translation.x, ppos.translation.y - bpos.translation.y) < critical_dist &&
-            !p.has_hit(&bloon.bid) {
-                // damage the bloon
-                damage_events.push(BloonDamageEvent { damage: p.damage, status_effect: None, bloon: be });
-                p.hit_bloons.push(bloon.bid.clone());
+/// Deterministic: collision checks run in parallel over fixed chunks of projectiles, but the hits are resolved on one thread in spawn order
+/// (`DamageDealer::seq`, not query order, which changes with storage history and after loading), so pierce runs out the same way every run. A bloon that was already popped this tick by an earlier projectile doesn't take pierce from later ones.
+/// Projectiles with SeekAfterHitMove remember the last bloon they hit if they survive it, so that they can turn away from it.
+/// Blowbacks of every projectile that hit a bloon this tick add up.
+/// A hit on a bloon the projectile can't pop (lead for sharp, frozen for sharp, ...) uses up pierce, but does nothing to the bloon.
+pub fn damage_bloons(
+    mut cmd: Commands,
+    mut damage_ew: EventWriter<BloonDamageEvent>,
+    bloons: Query<(Entity, &Bloon, &MoveAlongRoad, &HitboxSimple, &Transform)>,
+    mut p: Query<(Entity, &mut DamageDealer, &HitboxSimple, &Transform, Option<&mut SeekAfterHitMove>, Option<&BlowbackDealer>, Option<&EffectDealer>)>,
+) {
+    // find candidates in parallel; `scope` returns results in spawn order, so the chunking doesn't affect the outcome
+    let projectiles: Vec<_> = p.iter().collect();
+    let chunk_size = (projectiles.len() / ComputeTaskPool::get().thread_num().max(1)).max(64);
+    let mut candidates: Vec<(u64, Entity, Vec<HitCandidate>)> = ComputeTaskPool::get().scope(|s| {
+        for chunk in projectiles.chunks(chunk_size) {
+            let bloons = &bloons;
+            s.spawn(async move {
+                chunk.iter()
+                .map(|(pe, p, phb, ppos, ..)| (p.seq, *pe, find_hit_candidates(p, phb, ppos, bloons)))
+                .filter(|(_, _, c)| !c.is_empty())
+                .collect::<Vec<_>>()
+            });
+        }
+    }).into_iter().flatten().collect();
+    drop(projectiles);
+    candidates.sort_unstable_by_key(|(seq, pe, _)| (*seq, *pe)); // entity only breaks ties between dealers spawned outside the simulation
+
+    // resolve hits in a fixed order
+    let mut remaining_hp: HashMap<Entity, i32> = HashMap::default();
+    let mut damage_events = vec![];
+    let mut blowbacks: Vec<(Entity, f32)> = vec![];
+    for (_, pe, bloon_candidates) in candidates {
+        let Ok((_, mut p, _, _, seek, blowback, effects)) = p.get_mut(pe) else { continue; };
+        let mut hit = None;
+        for candidate in bloon_candidates {
+            let hp = remaining_hp.entry(candidate.bloon).or_insert(candidate.hp);
+            if *hp <= 0 { continue; } // popped earlier this tick; its children can be hit next tick
+            if candidate.modifiers & p.cannot_pop_modifiers != 0 {
+                p.hit_bloons.insert(&candidate.bid);
                 p.pierce -= 1;
-                if p.pierce == 0 { cmd.command_scope(|mut cmd| { cmd.entity(pe).despawn(); }); break; }
+                if p.pierce == 0 { cmd.entity(pe).despawn(); break; }
+                continue;
             }
+            *hp -= p.damage;
+            // damage the bloon
+            let status_effects = effects.map_or(vec![], |e| e.effects.clone());
+            damage_events.push(BloonDamageEvent { damage: p.damage, status_effects, bloon: candidate.bloon });
+            if let Some(blowback) = blowback {
+                match blowbacks.iter_mut().find(|(e, _)| *e == candidate.bloon) {
+                    Some((_, distance)) => *distance += blowback.distance,
+                    None => blowbacks.push((candidate.bloon, blowback.distance)),
+                }
+            }
+            p.hit_bloons.insert(&candidate.bid);
+            hit = Some(candidate.bid.clone());
+            p.pierce -= 1;
+            if p.pierce == 0 { cmd.entity(pe).despawn(); break; }
         }
-        // this is fine, since it's a lock at the end, only once; although the `if` seemingly made it worse for a little while??? and then just not??
-        if damage_events.len() > 0 {
-            let mut val = mutex.lock().unwrap();
-            val.send_batch(damage_events);
+        if let (Some(hit), Some(mut seek)) = (hit, seek) {
+            if p.pierce > 0 { seek.last_hit = Some(hit); }
         }
-    });
+    }
+    for (e, distance) in blowbacks {
+        cmd.entity(e).insert(BlowbackEffect { distance });
+    }
+    damage_ew.send_batch(damage_events);
 }
 
 pub fn lifetime_tick(mut cmd: Commands, mut lifetimes: Query<(Entity, &mut LifetimeTick)>) {
@@ -83,11 +185,60 @@ pub fn lifetime_tick(mut cmd: Commands, mut lifetimes: Query<(Entity, &mut Lifet
     }
 }
 
+/// Count down round based lifetimes when a round ends
+pub fn lifetime_round(mut cmd: Commands, mut round_end_er: EventReader<RoundEndEvent>, mut lifetimes: Query<(Entity, &mut LifetimeRound)>) {
+    for _ in round_end_er.read() {
+        for (e, mut lifetime) in &mut lifetimes {
+            lifetime.lifetime_rounds -= 1;
+            if lifetime.lifetime_rounds <= 0 {
+                cmd.entity(e).despawn();
+            }
+        }
+    }
+}
+
+/// Collect leftover items and clear projectiles that are about to expire anyway, as configured in `GameState::round_end_sweep`
+pub fn round_end_sweep(
+    mut cmd: Commands,
+    mut round_end_er: EventReader<RoundEndEvent>,
+    mut game: ResMut<GameState>,
+    items: Query<(Entity, &Collectible)>,
+    projectiles: Query<(Entity, &LifetimeTick), Without<LifetimeRound>>,
+) {
+    for _ in round_end_er.read() {
+        for (e, item) in &items {
+            if game.round_end_sweep.collect_items { game.cash += item.cash; }
+            cmd.entity(e).despawn();
+        }
+        let Some(under) = game.round_end_sweep.clear_lifetime_under else { continue; };
+        for (e, lifetime) in &projectiles {
+            if lifetime.lifetime < under { cmd.entity(e).despawn(); }
+        }
+    }
+}
+
 /*
     Misc
 */
 
+/// A basic straight-flying projectile
+pub fn simple_projectile(damage: i32, pierce: u32, velocity: Vec2)->(MoveSimple, DamageDealer, Transform, HitboxSimple, LifetimeTick, Sprite) {
+    return (
+        MoveSimple { velocity, bounce: 0, collide_height: None },
+        DamageDealer { damage: damage, pierce: pierce, cannot_pop_modifiers: (0 as BloonModifier), cannot_target_modifiers: (0 as BloonModifier), hit_bloons: HitSet::default(), seq: 0 },
+        Transform::from_translation(vec3(0.,0.,1.)),
+        HitboxSimple { radius: 5. },
+        LifetimeTick { lifetime: 40 },
+        get_projectile_sprite(),
+    );
+}
+
 /// should not be here TODO
 pub fn get_projectile_sprite()->Sprite {
     Sprite::from_color(Color::srgb(1.,0.,0.), vec2(10., 10.))
+}
+
+/// should not be here TODO
+pub fn get_collectible_sprite()->Sprite {
+    Sprite::from_color(Color::srgb(1.,0.9,0.2), vec2(12., 12.))
 }
\ No newline at end of file
@@ -0,0 +1,216 @@
+use std::{error::Error, fs, time::Duration};
+
+use bevy::{prelude::*, time::TimeUpdateStrategy};
+use serde::{Deserialize, Serialize};
+
+use crate::core::{command::{PendingPlayerCommands, PlayerCommand}, game::SimTick, map::Map, sim_rng::SimRng, state_hash::StateHash, BTD0CorePlugin};
+
+/// Every how many ticks the state hash is recorded (and checked on playback)
+pub const CHECKPOINT_INTERVAL: usize = 64;
+
+/*
+    Replay data
+*/
+
+/// Everything needed to play a game back: the seed, the map and every player command with its tick
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Replay {
+    pub seed: u64,
+    pub map: i32,
+    pub commands: Vec<ReplayCommand>,
+    pub checkpoints: Vec<Checkpoint>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReplayCommand {
+    pub tick: usize,
+    pub command: PlayerCommand,
+}
+
+/// State hash at a given tick (after that tick was simulated)
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct Checkpoint {
+    pub tick: usize,
+    pub hash: u64,
+}
+
+impl Replay {
+    pub fn load(path: &str)->Result<Self, Box<dyn Error>> {
+        return Ok(ron::from_str(&fs::read_to_string(path)?)?);
+    }
+    pub fn save(&self, path: &str)->Result<(), Box<dyn Error>> {
+        fs::write(path, ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?)?;
+        return Ok(());
+    }
+    /// Last tick at which something happens in this replay
+    pub fn last_tick(&self)->usize {
+        let last_command = self.commands.last().map_or(0, |c| c.tick);
+        let last_checkpoint = self.checkpoints.last().map_or(0, |c| c.tick);
+        return last_command.max(last_checkpoint);
+    }
+}
+
+/// Records the commands and checkpoints of the game that's currently being played
+#[derive(Resource, Default)]
+pub struct ReplayRecorder {
+    pub commands: Vec<ReplayCommand>,
+    pub checkpoints: Vec<Checkpoint>,
+    pub from_save: bool, // the game was loaded from a save, so it can't be played back from the start
+}
+
+impl ReplayRecorder {
+    pub fn to_replay(&self, rng: &SimRng, map: &Map)->Replay {
+        return Replay { seed: rng.seed(), map: map.id, commands: self.commands.clone(), checkpoints: self.checkpoints.clone() };
+    }
+}
+
+/// Present only while playing a replay back. Live input is ignored while it exists.
+#[derive(Resource)]
+pub struct ReplayPlayback {
+    pub replay: Replay,
+    next_command: usize,
+    next_checkpoint: usize,
+    pub desync: Option<usize>, // first tick at which the hashes didn't match
+}
+
+impl ReplayPlayback {
+    pub fn new(replay: Replay)->Self {
+        return ReplayPlayback { replay, next_command: 0, next_checkpoint: 0, desync: None };
+    }
+}
+
+/*
+    Systems
+*/
+
+/// Take the commands for this tick (from input, or from the replay when playing one back), record them and send them out
+pub fn collect_player_commands(tick: Res<SimTick>, mut pending: ResMut<PendingPlayerCommands>, mut recorder: ResMut<ReplayRecorder>, playback: Option<ResMut<ReplayPlayback>>, mut commands_ew: EventWriter<PlayerCommand>) {
+    if let Some(mut playback) = playback {
+        pending.clear();
+        while let Some(c) = playback.replay.commands.get(playback.next_command) {
+            if c.tick > tick.0 { break; }
+            commands_ew.send(c.command.clone());
+            playback.next_command += 1;
+        }
+        return;
+    }
+    for command in pending.drain(..) {
+        recorder.commands.push(ReplayCommand { tick: tick.0, command: command.clone() });
+        commands_ew.send(command);
+    }
+}
+
+/// Every `CHECKPOINT_INTERVAL` ticks, record the state hash, or compare it to the recorded one when playing back
+pub fn replay_checkpoint(tick: Res<SimTick>, state_hash: Res<StateHash>, mut recorder: ResMut<ReplayRecorder>, playback: Option<ResMut<ReplayPlayback>>) {
+    if tick.0 % CHECKPOINT_INTERVAL != 0 { return; }
+    let hash = state_hash.hash;
+    if let Some(mut playback) = playback {
+        if let Some(checkpoint) = playback.replay.checkpoints.get(playback.next_checkpoint).copied() {
+            if checkpoint.tick != tick.0 { return; }
+            playback.next_checkpoint += 1;
+            if checkpoint.hash != hash && playback.desync.is_none() {
+                error!("Replay desync at tick {}", tick.0);
+                playback.desync = Some(tick.0);
+            }
+        }
+        return;
+    }
+    recorder.checkpoints.push(Checkpoint { tick: tick.0, hash });
+}
+
+/*
+    Headless playback
+*/
+
+/// Result of a headless playback
+pub struct ReplayResult {
+    pub desync: Option<usize>, // first tick at which the state didn't match the recording
+    pub hash_log: Option<Vec<(usize, u64)>>, // state hash of every tick, if asked for
+}
+
+/// A simulation without a window. `log_hashes` keeps the state hash of every tick.
+pub fn headless_app(seed: u64, map: i32, log_hashes: bool)->App {
+    let mut app = App::new();
+    app.add_plugins((MinimalPlugins, BTD0CorePlugin))
+    .insert_resource(SimRng::new(seed))
+    .insert_resource(Map::get_map(map));
+    if log_hashes { app.insert_resource(StateHash::with_log()); }
+    app.finish();
+    app.cleanup();
+    return app;
+}
+
+/// Run exactly one fixed tick of a headless app (the timestep can change mid-game with speed commands)
+pub fn step_headless(app: &mut App) {
+    let step: Duration = app.world().resource::<Time<Fixed>>().timestep();
+    app.insert_resource(TimeUpdateStrategy::ManualDuration(step));
+    app.update();
+}
+
+/// Play a replay back without a window, as fast as possible
+pub fn run_replay(replay: Replay, log_hashes: bool)->ReplayResult {
+    let last_tick = replay.last_tick();
+    let mut app = headless_app(replay.seed, replay.map, log_hashes);
+    app.insert_resource(ReplayPlayback::new(replay));
+
+    while app.world().resource::<SimTick>().0 <= last_tick {
+        step_headless(&mut app);
+    }
+    return ReplayResult {
+        desync: app.world().resource::<ReplayPlayback>().desync,
+        hash_log: app.world_mut().resource_mut::<StateHash>().log.take(),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::math::vec2;
+    use crate::core::{bloon::BloonTier, tower::{TowerId, TowerType}};
+
+    /// Play a session headlessly like a player would, queueing each command at its tick, and return its recording
+    fn record(seed: u64, map: i32, commands: &[(usize, PlayerCommand)], ticks: usize)->Replay {
+        let mut app = headless_app(seed, map, false);
+        while app.world().resource::<SimTick>().0 <= ticks {
+            // `collect_player_commands` runs after the tick is advanced
+            let next = app.world().resource::<SimTick>().0 + 1;
+            for (_, command) in commands.iter().filter(|(tick, _)| *tick == next) {
+                app.world_mut().resource_mut::<PendingPlayerCommands>().push(command.clone());
+            }
+            step_headless(&mut app);
+        }
+        let world = app.world();
+        return world.resource::<ReplayRecorder>().to_replay(world.resource::<SimRng>(), world.resource::<Map>());
+    }
+
+    #[test]
+    fn playback_is_deterministic() {
+        let commands = [
+            (1, PlayerCommand::PlaceTower { tower_type: TowerType::DartMonkey, pos: vec2(-150., 0.) }),
+            (1, PlayerCommand::PlaceTower { tower_type: TowerType::GlueMonkey, pos: vec2(180., 150.) }),
+            (2, PlayerCommand::UpgradeTower { tower: TowerId(0), path: 1 }),
+            (3, PlayerCommand::SpawnBloon { tier: BloonTier::Ceramic }),
+            (3, PlayerCommand::SpawnBloon { tier: BloonTier::Lead }),
+            (4, PlayerCommand::StartRound),
+            (40, PlayerCommand::SpawnBloon { tier: BloonTier::Rainbow }),
+            (200, PlayerCommand::SetTargeting { tower: TowerId(1), mode: 1 }),
+            (500, PlayerCommand::SellTower { tower: TowerId(0) }),
+            (520, PlayerCommand::PlaceTower { tower_type: TowerType::DartMonkey, pos: vec2(0., 60.) }),
+        ];
+        let replay = record(7, 1, &commands, 640);
+        assert!(!replay.commands.is_empty() && !replay.checkpoints.is_empty());
+
+        let mut tampered = replay.clone();
+        tampered.checkpoints[1].hash ^= 1;
+        assert_eq!(run_replay(tampered, false).desync, Some(replay.checkpoints[1].tick));
+
+        let first = run_replay(replay.clone(), true);
+        let second = run_replay(replay, true);
+        assert_eq!(first.desync, None);
+        assert_eq!(second.desync, None);
+        let (first, second) = (first.hash_log.unwrap(), second.hash_log.unwrap());
+        assert!(first.len() >= 640);
+        assert!(first.windows(2).any(|w| w[0].1 != w[1].1));
+        assert!(first == second);
+    }
+}
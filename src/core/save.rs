@@ -0,0 +1,308 @@
+use std::{error::Error, fs};
+
+use bevy::{prelude::*, utils::HashMap};
+use serde::{Deserialize, Serialize};
+
+use crate::core::{
+    ability::Abilities,
+    bloon::{get_bloon_sprite, Bloon, BloonPropertyRegrow},
+    command::PendingPlayerCommands,
+    game::{GameState, SimTick},
+    hero::Hero,
+    hitbox::HitboxSimple,
+    map::Map,
+    movement::{Bounce, HomingMove, MoveAlongRoad, MoveSimple, MoveWaypoint, SeekAfterHitMove, SteeringMove, TargetMode},
+    projectile::{get_collectible_sprite, get_projectile_sprite, BlowbackDealer, Collectible, DamageDealer, EffectDealer, Deployed, LifetimeRound, LifetimeTick},
+    replay::ReplayRecorder,
+    sim_rng::SimRng,
+    state_hash::StateHash,
+    subtower::{get_subtower_sprite, Subtower},
+    tower::{get_tower_sprite, Tower, TowerId, TowerSave},
+    tower_def::TowerDefinitions,
+    zombie::{get_zombie_sprite, Zombie},
+};
+
+/// Bump whenever the save format changes
+pub const SAVE_VERSION: u32 = 1;
+
+/*
+    Save data
+*/
+
+/// A snapshot of a game in progress, taken between ticks. Everything the simulation depends on is in it (including the spawn order of projectiles,
+/// which decides the order hits are resolved in), so loading it and continuing should play out like the original game would have.
+/// Nothing that's outside the simulation is kept: input that hasn't been applied yet, the replay being recorded and the state hash log start over on load.
+/// (Replays only cover games played from the start; a game continued from a save can't be played back.)
+#[derive(Serialize, Deserialize)]
+pub struct SaveGame {
+    pub version: u32,
+    pub map: i32,
+    pub tick: usize,
+    pub tick_hz: f64, // game speed
+    pub rng: SimRng,
+    pub game: GameState,
+    pub towers: Vec<SavedTower>,
+    pub bloons: Vec<SavedBloon>,
+    pub projectiles: Vec<SavedProjectile>,
+    pub zombies: Vec<SavedZombie>,
+}
+
+// `entity` fields are the entities at the time of saving; they are only used to reconnect references (homing targets etc) on load
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedTower {
+    pub entity: Entity,
+    pub id: TowerId,
+    pub pos: Vec3,
+    pub tower: TowerSave,
+    pub hero: Option<Hero>,
+    pub abilities: Vec<(String, i32)>, // cooldown left of each ability, by name
+    pub subtowers: Vec<SavedSubtower>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedSubtower {
+    pub id: TowerId,
+    pub pos: Vec3, // relative to the parent
+    pub tower: TowerSave,
+    pub subtower: Subtower,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedBloon {
+    pub entity: Entity,
+    pub pos: Vec3,
+    pub bloon: Bloon,
+    pub movement: MoveAlongRoad,
+    pub hitbox: HitboxSimple,
+    pub regrow: Option<BloonPropertyRegrow>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedZombie {
+    pub pos: Vec3,
+    pub zombie: Zombie,
+    pub movement: MoveAlongRoad,
+    pub hitbox: HitboxSimple,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedProjectile {
+    pub entity: Entity,
+    pub pos: Vec3,
+    pub hitbox: Option<HitboxSimple>,
+    pub damage: Option<DamageDealer>,
+    pub lifetime: Option<LifetimeTick>,
+    pub lifetime_rounds: Option<LifetimeRound>,
+    pub move_simple: Option<MoveSimple>,
+    pub move_waypoint: Option<MoveWaypoint>,
+    pub deployed: bool,
+    pub collectible: Option<Collectible>,
+    pub steering: Option<SteeringMove>,
+    pub homing: Option<HomingMove>,
+    pub seek_after_hit: Option<SeekAfterHitMove>,
+    pub bounce: Option<Bounce>,
+    pub blowback: Option<BlowbackDealer>,
+    pub effects: Option<EffectDealer>,
+}
+
+impl SaveGame {
+    pub fn load(path: &str)->Result<Self, Box<dyn Error>> {
+        let save: SaveGame = ron::from_str(&fs::read_to_string(path)?)?;
+        if save.version != SAVE_VERSION {
+            return Err(format!("save version {} is not supported (expected {})", save.version, SAVE_VERSION).into());
+        }
+        return Ok(save);
+    }
+    pub fn save(&self, path: &str)->Result<(), Box<dyn Error>> {
+        fs::write(path, ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?)?;
+        return Ok(());
+    }
+}
+
+/// Entities that are saved as projectiles: anything that moves on its own, deals damage or can be collected and isn't a bloon or a tower
+type ProjectileFilter = (Or<(With<DamageDealer>, With<MoveSimple>, With<MoveWaypoint>, With<Collectible>)>, Without<Bloon>, Without<Tower>);
+
+/*
+    Saving and loading
+*/
+
+/// Take a snapshot of the current game
+pub fn save_game(world: &mut World)->SaveGame {
+    let mut subtowers: HashMap<Entity, Vec<SavedSubtower>> = HashMap::default();
+    for (parent, id, tower, pos, subtower) in world.query::<(&Parent, &TowerId, &Tower, &Transform, &Subtower)>().iter(world) {
+        subtowers.entry(parent.get()).or_default().push(SavedSubtower { id: *id, pos: pos.translation, tower: tower.to_save(), subtower: subtower.clone() });
+    }
+    let towers = world.query_filtered::<(Entity, &TowerId, &Tower, &Transform, Option<&Hero>, &Abilities), Without<Subtower>>().iter(world)
+        .map(|(entity, id, tower, pos, hero, abilities)| {
+            let mut subtowers = subtowers.remove(&entity).unwrap_or_default();
+            subtowers.sort_by_key(|s| s.id);
+            SavedTower { entity, id: *id, pos: pos.translation, tower: tower.to_save(), hero: hero.cloned(), abilities: abilities.to_save(), subtowers }
+        })
+        .collect();
+    let bloons = world.query::<(Entity, &Bloon, &MoveAlongRoad, &HitboxSimple, &Transform, Option<&BloonPropertyRegrow>)>().iter(world)
+        .map(|(entity, bloon, re, hb, pos, regrow)| SavedBloon {
+            entity, pos: pos.translation, bloon: bloon.clone(), movement: re.clone(), hitbox: hb.clone(), regrow: regrow.cloned(),
+        })
+        .collect();
+    let projectiles = world.query_filtered::<(
+        Entity, &Transform, Option<&HitboxSimple>, Option<&DamageDealer>, Option<&LifetimeTick>, Option<&LifetimeRound>,
+        Option<&MoveSimple>, Option<&MoveWaypoint>, Has<Deployed>, Option<&Collectible>,
+        Option<&SteeringMove>, Option<&HomingMove>, Option<&SeekAfterHitMove>, (Option<&Bounce>, Option<&BlowbackDealer>, Option<&EffectDealer>),
+    ), ProjectileFilter>().iter(world)
+        .map(|(entity, pos, hb, dd, lt, lr, ms, mw, dp, co, st, ho, sk, (bo, bb, ef))| SavedProjectile {
+            entity, pos: pos.translation, hitbox: hb.cloned(), damage: dd.cloned(), lifetime: lt.cloned(), lifetime_rounds: lr.cloned(),
+            move_simple: ms.cloned(), move_waypoint: mw.cloned(), deployed: dp, collectible: co.cloned(), steering: st.cloned(), homing: ho.cloned(), seek_after_hit: sk.cloned(), bounce: bo.cloned(), blowback: bb.cloned(), effects: ef.cloned(),
+        })
+        .collect();
+    let zombies = world.query::<(&Zombie, &MoveAlongRoad, &HitboxSimple, &Transform)>().iter(world)
+        .map(|(zombie, re, hb, pos)| SavedZombie { pos: pos.translation, zombie: zombie.clone(), movement: re.clone(), hitbox: hb.clone() })
+        .collect();
+    return SaveGame {
+        version: SAVE_VERSION,
+        map: world.resource::<Map>().id,
+        tick: world.resource::<SimTick>().0,
+        tick_hz: 1. / world.resource::<Time<Fixed>>().timestep().as_secs_f64(),
+        rng: world.resource::<SimRng>().clone(),
+        game: world.resource::<GameState>().clone(),
+        towers,
+        bloons,
+        projectiles,
+        zombies,
+    };
+}
+
+/// Replace the current game with a saved one
+pub fn load_game(world: &mut World, save: SaveGame) {
+    // clear the current game
+    let old: Vec<Entity> = world.query_filtered::<Entity, Or<(With<Tower>, With<Bloon>, With<Zombie>, ProjectileFilter)>>().iter(world).collect();
+    for e in old {
+        if let Ok(e) = world.get_entity_mut(e) { e.despawn_recursive(); }
+    }
+
+    world.insert_resource(Map::get_map(save.map));
+    world.insert_resource(SimTick(save.tick));
+    world.resource_mut::<Time<Fixed>>().set_timestep_hz(save.tick_hz);
+    world.insert_resource(save.rng);
+    world.insert_resource(save.game);
+    world.resource_mut::<PendingPlayerCommands>().clear();
+    world.insert_resource(ReplayRecorder { from_save: true, ..default() });
+    let mut state_hash = world.resource_mut::<StateHash>();
+    (state_hash.tick, state_hash.hash) = (save.tick, 0);
+    if let Some(log) = &mut state_hash.log { log.clear(); }
+
+    // old entity -> new entity
+    let mut entity_map: HashMap<Entity, Entity> = HashMap::default();
+    let towers: Vec<_> = save.towers.into_iter().map(|t| {
+        let defs = world.resource::<TowerDefinitions>();
+        let tower = Tower::from_save(defs, &t.tower);
+        let abilities = Abilities::from_save(defs, &tower, &t.abilities);
+        let subtowers: Vec<_> = t.subtowers.iter().map(|s| (s.id, s.pos, Tower::from_save(defs, &s.tower), s.subtower.clone())).collect();
+        (t, tower, abilities, subtowers)
+    }).collect();
+    for (t, tower, abilities, subtowers) in towers {
+        let mut e = world.spawn((tower, abilities, t.id, Transform::from_translation(t.pos), get_tower_sprite()));
+        if let Some(hero) = t.hero { e.insert(hero); }
+        e.with_children(|c| {
+            for (id, pos, tower, subtower) in subtowers {
+                c.spawn((tower, id, subtower, Transform::from_translation(pos), get_subtower_sprite()));
+            }
+        });
+        entity_map.insert(t.entity, e.id());
+    }
+    for b in save.bloons {
+        let sprite = get_bloon_sprite(b.bloon.tier); // TODO: sprites should not be here
+        let mut e = world.spawn((b.bloon, b.movement, b.hitbox, Transform::from_translation(b.pos), sprite));
+        if let Some(regrow) = b.regrow { e.insert(regrow); }
+        entity_map.insert(b.entity, e.id());
+    }
+    for z in save.zombies {
+        let sprite = get_zombie_sprite(z.zombie.tier); // TODO: sprites should not be here
+        world.spawn((z.zombie, z.movement, z.hitbox, Transform::from_translation(z.pos), sprite));
+    }
+    let mut projectiles = vec![];
+    for p in save.projectiles {
+        let sprite = if p.collectible.is_some() { get_collectible_sprite() } else { get_projectile_sprite() };
+        let mut e = world.spawn((Transform::from_translation(p.pos), sprite));
+        if let Some(c) = p.hitbox { e.insert(c); }
+        if let Some(c) = p.damage { e.insert(c); }
+        if let Some(c) = p.lifetime { e.insert(c); }
+        if let Some(c) = p.lifetime_rounds { e.insert(c); }
+        if let Some(c) = p.move_simple { e.insert(c); }
+        if let Some(c) = p.move_waypoint { e.insert(c); }
+        if p.deployed { e.insert(Deployed); }
+        if let Some(c) = p.collectible { e.insert(c); }
+        if let Some(c) = p.steering { e.insert(c); }
+        if let Some(c) = p.homing { e.insert(c); }
+        if let Some(c) = p.seek_after_hit { e.insert(c); }
+        if let Some(c) = p.bounce { e.insert(c); }
+        if let Some(c) = p.blowback { e.insert(c); }
+        if let Some(c) = p.effects { e.insert(c); }
+        entity_map.insert(p.entity, e.id());
+        projectiles.push(e.id());
+    }
+
+    // reconnect entity references; a reference to something that wasn't saved falls back to the waypoint
+    let remap = |target: &mut TargetMode| {
+        if let TargetMode::Entity(e) = target {
+            *target = entity_map.get(e).map_or(TargetMode::Waypoint, |new| TargetMode::Entity(*new));
+        }
+    };
+    for e in projectiles {
+        if let Some(mut steering) = world.get_mut::<SteeringMove>(e) { remap(&mut steering.target); }
+        if let Some(mut homing) = world.get_mut::<HomingMove>(e) { remap(&mut homing.target); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::vec2;
+
+    use crate::core::{bloon::BloonTier, command::PlayerCommand, game::BASE_TICK_HZ, replay::{headless_app, step_headless}, tower::TowerType};
+
+    use super::*;
+
+    fn queue(app: &mut App, command: PlayerCommand) {
+        app.world_mut().resource_mut::<PendingPlayerCommands>().push(command);
+    }
+
+    /// A fresh app with `save` loaded into it
+    fn loaded(save: SaveGame)->App {
+        let mut app = headless_app(0, save.map, false);
+        // the first update only starts the clock; no tick runs in it
+        step_headless(&mut app);
+        assert_eq!(app.world().resource::<SimTick>().0, 0);
+        load_game(app.world_mut(), save);
+        return app;
+    }
+
+    #[test]
+    fn save_load_save_plays_on_the_same() {
+        let mut original = headless_app(5, 1, false);
+        queue(&mut original, PlayerCommand::PlaceTower { tower_type: TowerType::DartMonkey, pos: vec2(-150., 0.) });
+        queue(&mut original, PlayerCommand::PlaceTower { tower_type: TowerType::GlueMonkey, pos: vec2(180., 150.) });
+        queue(&mut original, PlayerCommand::SpawnBloon { tier: BloonTier::Ceramic });
+        queue(&mut original, PlayerCommand::StartRound);
+        queue(&mut original, PlayerCommand::SetSpeed { hz: BASE_TICK_HZ * 4. });
+        for _ in 0..150 { step_headless(&mut original); }
+
+        // through text, like a save file
+        let save = save_game(original.world_mut());
+        assert_eq!(save.towers.len(), 2);
+        assert!(!save.bloons.is_empty() && !save.projectiles.is_empty());
+        let text = ron::to_string(&save).unwrap();
+        let mut first = loaded(ron::from_str(&text).unwrap());
+        let mut second = loaded(save_game(first.world_mut()));
+        for app in [&first, &second] {
+            assert_eq!(app.world().resource::<Time<Fixed>>().timestep(), original.world().resource::<Time<Fixed>>().timestep());
+        }
+        for _ in 0..200 {
+            for app in [&mut original, &mut first, &mut second] { step_headless(app); }
+            let hash = original.world().resource::<StateHash>();
+            for app in [&first, &second] {
+                let loaded = app.world().resource::<StateHash>();
+                assert_eq!((loaded.tick, loaded.hash), (hash.tick, hash.hash));
+            }
+        }
+    }
+}
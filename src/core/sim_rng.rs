@@ -0,0 +1,79 @@
+use bevy::prelude::*;
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::core::bloon::BIDFamily;
+
+/// The only source of randomness (and of new bloon families) in the simulation.
+/// Same seed + same inputs = same game, which is what replays and leaderboard verification rely on.
+#[derive(Resource, Clone, Serialize, Deserialize)]
+#[serde(from = "SavedRng", into = "SavedRng")]
+pub struct SimRng {
+    seed: u64,
+    rng: ChaCha8Rng,
+    next_family: BIDFamily, // a counter rather than a random number - random families can collide
+}
+
+/// How `SimRng` is saved: the rng is its seed plus how far it got. (ChaCha's own serialization has a u128, which RON can't write.)
+#[derive(Serialize, Deserialize)]
+struct SavedRng {
+    seed: u64,
+    word_pos: (u64, u64), // high and low halves
+    next_family: BIDFamily,
+}
+
+impl From<SavedRng> for SimRng {
+    fn from(saved: SavedRng)->Self {
+        let mut rng = ChaCha8Rng::seed_from_u64(saved.seed);
+        rng.set_word_pos(((saved.word_pos.0 as u128) << 64) | saved.word_pos.1 as u128);
+        return SimRng { seed: saved.seed, rng, next_family: saved.next_family };
+    }
+}
+
+impl From<SimRng> for SavedRng {
+    fn from(rng: SimRng)->Self {
+        let word_pos = rng.rng.get_word_pos();
+        return SavedRng { seed: rng.seed, word_pos: ((word_pos >> 64) as u64, word_pos as u64), next_family: rng.next_family };
+    }
+}
+
+impl SimRng {
+    pub fn new(seed: u64)->Self {
+        return SimRng { seed, rng: ChaCha8Rng::seed_from_u64(seed), next_family: 0 };
+    }
+    /// Pick a random seed. The game is still deterministic from there on; the seed just has to be recorded.
+    pub fn from_entropy()->Self {
+        return SimRng::new(rand::random());
+    }
+    pub fn seed(&self)->u64 {
+        return self.seed;
+    }
+    /// Get a family id that no other bloon of this game has
+    pub fn next_family(&mut self)->BIDFamily {
+        let family = self.next_family;
+        self.next_family = self.next_family.wrapping_add(1);
+        return family;
+    }
+}
+
+impl RngCore for SimRng {
+    fn next_u32(&mut self)->u32 {
+        return self.rng.next_u32();
+    }
+    fn next_u64(&mut self)->u64 {
+        return self.rng.next_u64();
+    }
+    fn fill_bytes(&mut self, dst: &mut [u8]) {
+        self.rng.fill_bytes(dst);
+    }
+}
+
+/*
+    Systems
+*/
+
+/// Record the seed of this game
+pub fn log_seed(rng: Res<SimRng>) {
+    info!("Simulation seed: {}", rng.seed());
+}
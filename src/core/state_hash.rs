@@ -0,0 +1,147 @@
+use std::hash::{Hash, Hasher};
+
+use bevy::prelude::*;
+
+use crate::core::{bloon::Bloon, game::{GameState, SimTick}, movement::MoveAlongRoad, projectile::DamageDealer, tower::{Tower, TowerId}, zombie::Zombie};
+
+/// FNV-1a. Integers are fed in as fixed-width little-endian bytes (`usize`/`isize`, including lengths and enum discriminants, as 64 bits),
+/// instead of std's native-endian, pointer-sized ones, so that the same state hashes the same on every platform.
+pub struct FnvHasher(u64);
+
+impl Default for FnvHasher {
+    fn default()->Self {
+        return FnvHasher(0xcbf29ce484222325);
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn finish(&self)->u64 {
+        return self.0;
+    }
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+    // the signed ones go through these
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes());
+    }
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes());
+    }
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+}
+
+/// Hash of the canonical simulation state, updated at the end of every fixed tick.
+/// Two runs of the same game must produce the same hash every tick; the first tick they don't is where they diverged.
+#[derive(Resource, Default)]
+pub struct StateHash {
+    pub tick: usize,
+    pub hash: u64,
+    pub log: Option<Vec<(usize, u64)>>, // (tick, hash) of every tick, if enabled
+}
+
+impl StateHash {
+    pub fn with_log()->Self {
+        return StateHash { log: Some(vec![]), ..default() };
+    }
+}
+
+/*
+    Systems
+*/
+
+/// Hash bloons, projectiles, towers, zombies, cash and lives.
+/// Everything is sorted by value before hashing, so the hash doesn't depend on query iteration order - only on the actual state.
+pub fn hash_simulation_state(
+    tick: Res<SimTick>,
+    game: Res<GameState>,
+    bloons: Query<(&Bloon, &MoveAlongRoad)>,
+    projectiles: Query<(&DamageDealer, &Transform)>,
+    towers: Query<(&TowerId, &Tower, &Transform)>,
+    zombies: Query<(&Zombie, &MoveAlongRoad)>,
+    mut state_hash: ResMut<StateHash>,
+) {
+    let mut hasher = FnvHasher::default();
+    tick.0.hash(&mut hasher);
+    game.round.hash(&mut hasher);
+    game.cash.hash(&mut hasher);
+    game.lives.hash(&mut hasher);
+
+    let mut bloon_data: Vec<_> = bloons.iter().map(|(bloon, re)| (
+        (bloon.bid.family, bloon.bid.layer, bloon.bid.tree),
+        bloon.tier, bloon.hp, bloon.modifiers, re.road_pos.to_bits(),
+    )).collect();
+    bloon_data.sort_unstable();
+    bloon_data.hash(&mut hasher);
+
+    let mut projectile_data: Vec<_> = projectiles.iter().map(|(p, pos)| (
+        pos.translation.x.to_bits(), pos.translation.y.to_bits(), p.damage, p.pierce,
+    )).collect();
+    projectile_data.sort_unstable();
+    projectile_data.hash(&mut hasher);
+
+    let mut tower_data: Vec<_> = towers.iter().map(|(id, tower, pos)| (
+        *id, pos.translation.x.to_bits(), pos.translation.y.to_bits(), tower.get_upgrades(), tower.cur_targeting_mode(),
+    )).collect();
+    tower_data.sort_unstable_by_key(|t| t.0);
+    tower_data.hash(&mut hasher);
+
+    let mut zombie_data: Vec<_> = zombies.iter().map(|(z, re)| (z.tier, z.hp, re.road_pos.to_bits())).collect();
+    zombie_data.sort_unstable();
+    zombie_data.hash(&mut hasher);
+
+    state_hash.tick = tick.0;
+    state_hash.hash = hasher.finish();
+    let entry = (state_hash.tick, state_hash.hash);
+    if let Some(log) = &mut state_hash.log {
+        log.push(entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash_of(value: impl Hash)->u64 {
+        let mut hasher = FnvHasher::default();
+        value.hash(&mut hasher);
+        return hasher.finish();
+    }
+
+    fn hash_of_bytes(bytes: &[u8])->u64 {
+        let mut hasher = FnvHasher::default();
+        hasher.write(bytes);
+        return hasher.finish();
+    }
+
+    #[test]
+    fn integers_are_little_endian() {
+        assert_eq!(hash_of(0x0102_0304u32), hash_of_bytes(&[4, 3, 2, 1]));
+        assert_eq!(hash_of(-2i16), hash_of_bytes(&[0xfe, 0xff]));
+    }
+
+    #[test]
+    fn sizes_are_64_bits() {
+        assert_eq!(hash_of(5usize), hash_of(5u64));
+        assert_eq!(hash_of(-5isize), hash_of(-5i64));
+        // the length prefix too
+        assert_eq!(hash_of(vec![7u8]), hash_of_bytes(&[1, 0, 0, 0, 0, 0, 0, 0, 7]));
+    }
+
+    #[test]
+    fn known_value() {
+        // FNV-1a test vector
+        assert_eq!(hash_of_bytes(b"a"), 0xaf63dc4c8601ec8c);
+    }
+}
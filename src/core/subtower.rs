@@ -0,0 +1,129 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::core::{command::PlayerCommand, game::GameState, tower::{Tower, TowerId, TowerType}, tower_def::TowerDefinitions, upgradable::Upgradable};
+
+/*
+    Subtowers
+*/
+
+/// How a subtower moves around its parent
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum SubtowerMovement {
+    Fixed,
+    Follow { speed: f32 }, // flies towards the point the player picked, and stays there (heli)
+    Circuit { radius: f32, speed: f32 }, // circles around the parent (ace)
+}
+
+/// A subtower a tower prototype comes with
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SubtowerDef {
+    pub tower_type: TowerType,
+    #[serde(default)]
+    pub offset: Vec2, // starting position relative to the parent
+    pub movement: SubtowerMovement,
+}
+
+/// A tower that belongs to another tower: it's a hierarchy child of it (so its `Transform` is relative to the parent),
+/// can't be clicked, and has the parent's buffs instead of its own
+#[derive(Component, Clone, Serialize, Deserialize)]
+pub struct Subtower {
+    pub movement: SubtowerMovement,
+    pub target: Option<Vec2>, // relative to the parent; None means locked in place
+    pub angle: f32, // position on the circuit
+    #[serde(skip)]
+    inherited: Option<u32>, // parent's `Tower::stats_revision` its buffs were copied from
+}
+
+impl Subtower {
+    pub fn new(movement: SubtowerMovement)->Self {
+        return Subtower { movement, target: None, angle: 0., inherited: None };
+    }
+}
+
+/// Spawn subtowers as children of `parent`
+pub fn spawn_subtowers(cmd: &mut Commands, defs: &TowerDefinitions, game: &mut GameState, parent: Entity, subtowers: &[SubtowerDef]) {
+    for sub in subtowers {
+        let id = TowerId(game.next_tower_id);
+        game.next_tower_id += 1;
+        let subtower = Tower::from(defs, sub.tower_type, Upgradable::None);
+        cmd.entity(parent).with_children(|c| {
+            c.spawn((subtower, id, Subtower::new(sub.movement), Transform::from_xyz(sub.offset.x, sub.offset.y, 1.), get_subtower_sprite()));
+        });
+    }
+}
+
+/// Bring the subtowers of `parent` in line with `subtowers` after its prototype changed. The ones it already has are kept as they are
+/// (position, target, cooldowns and id) and only take the new movement; the missing ones are spawned, and the ones it doesn't come with anymore are despawned.
+pub fn update_subtowers(cmd: &mut Commands, defs: &TowerDefinitions, game: &mut GameState, parent: Entity, children: Option<&Children>, existing: &mut Query<(&mut Subtower, &Tower)>, subtowers: &[SubtowerDef]) {
+    let mut unmatched: Vec<Entity> = children.map_or(vec![], |c| c.iter().copied().filter(|c| existing.contains(*c)).collect());
+    let mut missing = vec![];
+    for sub in subtowers {
+        let found = unmatched.iter().position(|c| existing.get(*c).is_ok_and(|(_, t)| t.get_tower_type() == sub.tower_type));
+        let Some(i) = found else { missing.push(sub.clone()); continue; };
+        if let Ok((mut s, _)) = existing.get_mut(unmatched.remove(i)) {
+            s.movement = sub.movement;
+        }
+    }
+    for e in unmatched {
+        cmd.entity(e).despawn_recursive();
+    }
+    spawn_subtowers(cmd, defs, game, parent, &missing);
+}
+
+/// World position of a tower; subtowers are positioned relative to their parent
+pub fn tower_world_pos(pos: &Transform, parent: Option<&Parent>, parents: &Query<&Transform, (With<Tower>, Without<Parent>)>)->Vec2 {
+    let offset = parent.and_then(|p| parents.get(p.get()).ok()).map_or(Vec2::ZERO, |t| t.translation.truncate());
+    return pos.translation.truncate() + offset;
+}
+
+/*
+    Systems
+*/
+
+/// Point the movable subtowers of a tower somewhere (or lock them in place)
+pub fn apply_subtower_commands(mut commands: EventReader<PlayerCommand>, towers: Query<(&TowerId, &Transform, &Children), Without<Subtower>>, mut subtowers: Query<&mut Subtower>) {
+    for command in commands.read() {
+        let PlayerCommand::SetSubtowerTarget { tower, target } = command else { continue; };
+        let Some((_, pos, children)) = towers.iter().find(|(id, _, _)| **id == *tower) else { continue; };
+        for child in children {
+            let Ok(mut sub) = subtowers.get_mut(*child) else { continue; };
+            if let SubtowerMovement::Follow { .. } = sub.movement {
+                sub.target = target.map(|t| t - pos.translation.truncate());
+            }
+        }
+    }
+}
+
+pub fn move_subtowers(mut subtowers: Query<(&mut Subtower, &mut Transform)>) {
+    for (mut sub, mut pos) in &mut subtowers {
+        match sub.movement {
+            SubtowerMovement::Fixed => {},
+            SubtowerMovement::Follow { speed } => {
+                let Some(target) = sub.target else { continue; };
+                let cur = pos.translation.truncate();
+                let step = (target - cur).clamp_length_max(speed);
+                pos.translation += step.extend(0.);
+            },
+            SubtowerMovement::Circuit { radius, speed } => {
+                sub.angle = (sub.angle + speed / radius) % std::f32::consts::TAU;
+                pos.translation = (Vec2::from_angle(sub.angle) * radius).extend(pos.translation.z);
+            },
+        }
+    }
+}
+
+/// Subtowers have whatever buffs their parent has
+pub fn inherit_parent_buffs(parents: Query<&Tower, Without<Subtower>>, mut subtowers: Query<(&Parent, &mut Subtower, &mut Tower)>) {
+    for (parent, mut sub, mut tower) in &mut subtowers {
+        let Ok(parent) = parents.get(parent.get()) else { continue; };
+        if sub.inherited == Some(parent.get_stats_revision()) { continue; }
+        tower.inherit_buffs(parent);
+        sub.inherited = Some(parent.get_stats_revision());
+    }
+}
+
+// TODO: remove from here
+pub fn get_subtower_sprite()->Sprite {
+    return Sprite::from_color(Color::srgb(0.4, 0.5, 0.3), Vec2::new(24., 24.));
+}
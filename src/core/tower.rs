@@ -1,8 +1,9 @@
-use bevy::prelude::*;
+use bevy::{prelude::*, utils::HashSet};
+use serde::{Deserialize, Serialize};
 
-use crate::core::{attack_fn::*, bloon::BloonModifier, upgradable::Upgradable};
+use crate::core::{ability::Abilities, attack_fn::*, hero::Hero, bloon::{Bloon, BloonModifier, BloonModifierData}, game::SimTick, map::Map, movement::MoveAlongRoad, subtower::{spawn_subtowers, tower_world_pos, update_subtowers, Subtower, SubtowerDef}, buff::{BuffAura, BuffDef, BuffDuration, BuffKind, BuffStats, TowerBuff}, command::PlayerCommand, game::GameState, tower_def::{AttackDef, AttackKindDef, BounceStats, ProjectileStats, SeekStats, StatusStats, TowerDefinitions}, upgradable::Upgradable};
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum TowerType {
     //primary
     DartMonkey,BoomerangMonkey,TackShooter,BombShooter,IceMonkey,GlueMonkey,
@@ -20,102 +21,695 @@ pub enum TowerType {
     AceSubtower,HeliSubtower,BoatPlaneSubtower,HeliSupportSubtower,PhoenixSubtower,SunAvatarSubtower,EngineerTurretSubtower,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Serialize, Deserialize)]
 pub enum TargetingMode {
     FirstBloon, StrongBloon, LastBloon, CloseBloon,
     CloseRoad, FarRoad, SmartRoad,
     Always, InRange,
 }
 
+impl TowerType {
+    /// Subtowers only exist as part of another tower, and can't be placed
+    pub fn is_subtower(&self)->bool {
+        return matches!(self, TowerType::AceSubtower | TowerType::HeliSubtower | TowerType::BoatPlaneSubtower | TowerType::HeliSupportSubtower |
+            TowerType::PhoenixSubtower | TowerType::SunAvatarSubtower | TowerType::EngineerTurretSubtower);
+    }
+}
+
 impl TargetingMode {
     pub fn vec_entity_modes()->Vec<Self> {
         return vec![TargetingMode::FirstBloon,TargetingMode::StrongBloon,TargetingMode::LastBloon,TargetingMode::CloseBloon];
     }
 }
 
-#[derive(Clone)]
+/// What a buff does. Strengths of buffs that stack add up (attack rate multiplies).
+#[derive(Clone, Serialize, Deserialize)]
 pub enum TowerEffect {
-    Range { duration: Option<i32>, strength: f32 }, 
-    Damage { duration: Option<i32>, strength: i32 }, 
-    AttackRate { duration: Option<i32>, strength: f32 }, 
-    DetectionBuff { duration: Option<i32>, added: BloonModifier },
-    DamageTypeBuff { duration: Option<i32>, added: BloonModifier },
+    Range { strength: f32 }, // fraction of range added (0.1 is +10%)
+    Damage { strength: i32 },
+    Pierce { strength: i32 },
+    AttackRate { strength: f32 }, // multiplies the ticks between shots (0.85 is 15% faster)
+    DetectionBuff { added: BloonModifier },
+    DamageTypeBuff { added: BloonModifier },
 }
 
+/// What an attack is aimed at when it fires
 #[derive(Clone)]
-pub enum Attack {
-    TargetEntity {
-        range: f32,
-        attack_rate: i32,
+pub enum AttackTarget {
+    Bloon { angle: f32, entity: Entity, pos: Vec2 },
+    Angle(f32),
+    Road(Vec2),
+    Point(Vec2),
+    None, // always firing patterns
+    Tower { entity: Entity, pos: Vec2, source: TowerId },
+    Towers { entities: Vec<Entity>, source: TowerId },
+}
+
+impl AttackTarget {
+    /// Angle from `from` to the target
+    pub fn angle_from(&self, from: Vec2)->f32 {
+        return match self {
+            AttackTarget::Bloon { angle, .. } | AttackTarget::Angle(angle) => *angle,
+            AttackTarget::Road(pos) | AttackTarget::Point(pos) | AttackTarget::Tower { pos, .. } => (*pos - from).to_angle(),
+            AttackTarget::None | AttackTarget::Towers { .. } => 0.,
+        };
+    }
+}
+
+/// How an attack picks its target, and the target it picked last
+#[derive(Clone)]
+pub enum AttackKind {
+    TargetEntity { // single, instahit
         target_angle: Option<f32>,
         target_entity: Option<Entity>,
-        attack_fn: fn(&mut Commands, f32, Entity, &mut Vec<TowerEffect>, Vec3),
-        attack_at: usize, // fixed update tick number at which to shoot next time
     },
     TargetRoad {
-        range: f32,
-        attack_rate: i32,
         target_waypoint: Option<Vec2>,
-        attack_fn: fn(&mut Commands, Vec2, &mut Vec<TowerEffect>, Vec3),
-        attack_at: usize, // fixed update tick number at which to shoot next time
     },
+    ArcSpread { // aims at a bloon, but only cares about the angle
+        target_angle: Option<f32>,
+    },
+    Circle, // no target; fires whenever a round is on
+    Teleport { // the projectile appears at the target's position (mortar)
+        target_point: Option<Vec2>,
+    },
+    TargetTower { // a friendly tower in range that doesn't have the attack's buff yet (alch)
+        target_tower: Option<Entity>,
+    },
+    RangeBuff, // every friendly tower in range
+}
+
+#[derive(Clone)]
+pub struct Attack {
+    pub kind: AttackKind,
+    pub range: f32,
+    pub attack_rate: i32,
+    pub camo_detection: bool,
+    pub skip_glued: bool,
+    pub projectile: ProjectileStats,
+    pub buff: Option<BuffDef>,
+    pub attack_fn_name: &'static str,
+    pub attack_fn: AttackFn,
+    pub attack_at: usize, // fixed update tick number at which to shoot next time
+    pub state: AttackState,
+}
+
+/// The stats an attack actually fires with: its prototype stats with the tower's buffs applied
+#[derive(Clone, Default)]
+pub struct AttackStats {
+    pub buff: Option<BuffDef>, // what tower targeted attacks give
+    pub damage: i32,
+    pub pierce: u32,
+    pub range: f32,
+    pub cooldown: i32, // ticks between shots
+    pub cannot_pop: BloonModifier,
+    pub detection: BloonModifier, // modifiers (camo) it can see through
+    pub skip_glued: bool,
+    pub speed: f32,
+    pub multishot: u32,
+    pub spread: f32,
+    pub radius: f32,
+    pub lifetime: i32,
+    pub lifetime_rounds: Option<i32>,
+    pub cash: i64,
+    pub height: Option<f32>,
+    pub bounce: Option<BounceStats>,
+    pub seek: Option<SeekStats>,
+    pub blowback: f32,
+    pub status: Option<StatusStats>,
+}
+
+impl AttackStats {
+    pub fn from(attack: &Attack, buffs: &BuffStats)->Self {
+        let p = &attack.projectile;
+        return AttackStats {
+            buff: attack.buff.clone(),
+            damage: p.damage + buffs.damage,
+            pierce: (p.pierce as i32 + buffs.pierce).max(1) as u32,
+            range: attack.range * buffs.range_mult,
+            cooldown: ((attack.attack_rate as f32 * buffs.attack_rate_mult).round() as i32).max(1),
+            cannot_pop: p.damage_type.cannot_pop_modifiers() & !buffs.can_pop,
+            detection: if attack.camo_detection { BloonModifierData::Camo as BloonModifier } else { 0 } | buffs.detection,
+            skip_glued: attack.skip_glued,
+            speed: p.speed,
+            multishot: p.multishot,
+            spread: p.spread,
+            radius: p.radius,
+            lifetime: p.lifetime,
+            lifetime_rounds: p.lifetime_rounds,
+            cash: p.cash,
+            height: p.height,
+            bounce: p.bounce,
+            seek: p.seek,
+            blowback: p.blowback,
+            status: p.status,
+        };
+    }
+    /// Stats of projectiles that don't come from an attack (abilities): no buffs, and nothing to aim with
+    pub fn from_projectile(p: &ProjectileStats)->Self {
+        return AttackStats {
+            buff: None,
+            damage: p.damage,
+            pierce: p.pierce,
+            range: 0.,
+            cooldown: 1,
+            cannot_pop: p.damage_type.cannot_pop_modifiers(),
+            detection: 0,
+            skip_glued: false,
+            speed: p.speed,
+            multishot: p.multishot,
+            spread: p.spread,
+            radius: p.radius,
+            lifetime: p.lifetime,
+            lifetime_rounds: p.lifetime_rounds,
+            cash: p.cash,
+            height: p.height,
+            bounce: p.bounce,
+            seek: p.seek,
+            blowback: p.blowback,
+            status: p.status,
+        };
+    }
+}
+
+/// Cached `AttackStats` of every attack of a tower, in prototype order.
+/// Only recomputed when the tower's buffs or prototype change, not every time it fires.
+#[derive(Component, Default)]
+pub struct EffectiveAttackStats {
+    revision: Option<u32>, // `Tower::stats_revision` these were computed from
+    pub attacks: Vec<AttackStats>,
 }
 
 #[derive(Component)]
+#[require(EffectiveAttackStats, Abilities)]
 pub struct Tower {
     // prototype
     tower_type: TowerType,
     upgrades: Upgradable,
     attacks: Vec<Attack>, // although is technically modifiable (jerry fire)
     targeting_modes: Vec<TargetingMode>,
+    auras: Vec<BuffAura>, // buffs it gives to others
+    subtowers: Vec<SubtowerDef>,
     // state
     cur_targeting_mode: usize,
-    effects: Vec<TowerEffect>,
+    buffs: Vec<TowerBuff>, // buffs it got from others
+    buff_stats: BuffStats, // `buffs` added up
+    stats_revision: u32, // bumped whenever buffs or the prototype change
+    spent: i64, // total cash spent on this tower (placement and upgrades)
 }
 
 impl Tower {
     /// Dummy function because i didn't feel like doing default (it really shouldn't have a default, but I do need a dummy tower)
     fn zero()->Self {
-        Tower { attacks: vec![], tower_type: TowerType::DartMonkey, upgrades: Upgradable::None, targeting_modes: vec![TargetingMode::FirstBloon], cur_targeting_mode: 0, effects: vec![] }
-    }
-    pub fn from(tower_type: TowerType, upgrades: Upgradable)->Self {
-        match tower_type {
-            TowerType::DartMonkey => {
-                if let Upgradable::Crosspath520(p1, p2, p3) = upgrades {
-                    // DART MONKEY
-                    match (p1,p2,p3) {
-                        (0,0,0) => return Tower { attacks: vec![ Attack::TargetEntity { range: 100.0, attack_rate: 60, attack_fn: attack_dart000, attack_at: 0, target_angle: None, target_entity: None } ], tower_type, upgrades, targeting_modes: TargetingMode::vec_entity_modes(), cur_targeting_mode: 0, effects: vec![] },
-                        _ => {}
-                    }
-                }
-            },
-            _ => {},
+        Tower { attacks: vec![], tower_type: TowerType::DartMonkey, upgrades: Upgradable::None, targeting_modes: vec![TargetingMode::FirstBloon], auras: vec![], subtowers: vec![], cur_targeting_mode: 0, buffs: vec![], buff_stats: BuffStats::default(), stats_revision: 0, spent: 0 }
+    }
+    /// Build the prototype of a tower from its definition
+    pub fn from(defs: &TowerDefinitions, tower_type: TowerType, upgrades: Upgradable)->Self {
+        let Some(def) = defs.get(&tower_type) else {
+            // no definition yet; keep the identity so that it can still be saved, etc
+            return Tower { tower_type, upgrades, ..Tower::zero() };
+        };
+        let resolved = def.resolve(upgrades);
+        return Tower {
+            attacks: resolved.attacks.iter().map(Attack::from_def).collect(),
+            targeting_modes: resolved.targeting_modes,
+            auras: resolved.auras,
+            subtowers: resolved.subtowers,
+            tower_type,
+            upgrades,
+            ..Tower::zero()
         };
-        return Tower::zero();
+    }
+    /// Rebuild this tower's prototype for new upgrades.
+    /// Buffs, targeting mode and money spent are kept; attacks and targeting modes come from the new prototype.
+    /// An attack that is still the same attack function in the same place keeps its cooldown and state.
+    pub fn upgrade(&mut self, defs: &TowerDefinitions, upgrades: Upgradable) {
+        let mut upgraded = Tower::from(defs, self.tower_type, upgrades);
+        for (new, old) in upgraded.attacks.iter_mut().zip(&mut self.attacks) {
+            if new.attack_fn_name != old.attack_fn_name { continue; }
+            new.attack_at = old.attack_at;
+            new.state = std::mem::take(&mut old.state);
+        }
+        if !upgraded.set_targeting_mode(self.cur_targeting_mode) { upgraded.cur_targeting_mode = 0; }
+        upgraded.buffs = std::mem::take(&mut self.buffs);
+        upgraded.buff_stats = self.buff_stats.clone();
+        upgraded.stats_revision = self.stats_revision.wrapping_add(1);
+        upgraded.spent = self.spent;
+        *self = upgraded;
     }
     pub fn get_targeting_mode(&self)->TargetingMode {
         return self.targeting_modes[self.cur_targeting_mode];
     }
+    /// Return false if this tower doesn't have targeting mode number `mode`
+    pub fn set_targeting_mode(&mut self, mode: usize)->bool {
+        if mode >= self.targeting_modes.len() { return false; }
+        self.cur_targeting_mode = mode;
+        return true;
+    }
+    pub fn get_upgrades(&self)->Upgradable {
+        return self.upgrades;
+    }
+    pub fn get_tower_type(&self)->TowerType {
+        return self.tower_type;
+    }
+    pub fn get_spent(&self)->i64 {
+        return self.spent;
+    }
+    pub fn cur_targeting_mode(&self)->usize {
+        return self.cur_targeting_mode;
+    }
+    pub fn targeting_mode_count(&self)->usize {
+        return self.targeting_modes.len();
+    }
+    pub fn get_auras(&self)->&[BuffAura] {
+        return &self.auras;
+    }
+    pub fn get_buffs(&self)->&[TowerBuff] {
+        return &self.buffs;
+    }
+    pub fn get_buff_stats(&self)->&BuffStats {
+        return &self.buff_stats;
+    }
+    pub fn get_stats_revision(&self)->u32 {
+        return self.stats_revision;
+    }
+    pub fn get_subtowers(&self)->&[SubtowerDef] {
+        return &self.subtowers;
+    }
+    /// Replace this tower's buffs with a copy of `parent`'s (subtowers)
+    pub fn inherit_buffs(&mut self, parent: &Tower) {
+        self.buffs = parent.buffs.clone();
+        self.buffs_changed();
+    }
+    pub fn has_buff(&self, source: TowerId, kind: BuffKind)->bool {
+        return self.buffs.iter().any(|b| b.source == Some(source) && b.kind == kind);
+    }
+    /// Add a buff; getting the same buff from the same source again replaces it.
+    /// Either way it becomes the most recently applied one (the one that counts for `BuffStacking::Override`).
+    pub fn add_buff(&mut self, buff: TowerBuff) {
+        self.buffs.retain(|b| b.source != buff.source || b.kind != buff.kind);
+        self.buffs.push(buff);
+        self.buffs_changed();
+    }
+    pub fn retain_buffs(&mut self, f: impl FnMut(&TowerBuff)->bool) {
+        let len = self.buffs.len();
+        self.buffs.retain(f);
+        if self.buffs.len() != len { self.buffs_changed(); }
+    }
+    fn buffs_changed(&mut self) {
+        self.buff_stats = BuffStats::from_buffs(&self.buffs);
+        self.stats_revision = self.stats_revision.wrapping_add(1);
+    }
+    /// Remove every buff given by `source`
+    pub fn remove_buffs_from(&mut self, source: TowerId) {
+        self.retain_buffs(|buff| buff.source != Some(source));
+    }
+    /// Update the duration of every buff with `f`, and remove the ones that ran out
+    pub fn count_down_buffs(&mut self, mut f: impl FnMut(&mut BuffDuration)) {
+        for buff in &mut self.buffs {
+            f(&mut buff.duration);
+        }
+        self.retain_buffs(|b| !matches!(b.duration, BuffDuration::Ticks(0) | BuffDuration::Rounds(0)));
+    }
+}
+
+impl Attack {
+    /// Tower data is validated when it's loaded, so the attack function is known to exist
+    pub fn from_def(def: &AttackDef)->Self {
+        let (attack_fn_name, attack_fn) = get_attack_fn(&def.attack_fn).expect("attack functions are validated on load");
+        let kind = match def.kind {
+            AttackKindDef::TargetEntity => AttackKind::TargetEntity { target_angle: None, target_entity: None },
+            AttackKindDef::TargetRoad => AttackKind::TargetRoad { target_waypoint: None },
+            AttackKindDef::ArcSpread => AttackKind::ArcSpread { target_angle: None },
+            AttackKindDef::Circle => AttackKind::Circle,
+            AttackKindDef::Teleport => AttackKind::Teleport { target_point: None },
+            AttackKindDef::TargetTower => AttackKind::TargetTower { target_tower: None },
+            AttackKindDef::RangeBuff => AttackKind::RangeBuff,
+        };
+        return Attack { kind, range: def.range, attack_rate: def.attack_rate, camo_detection: def.camo_detection, skip_glued: def.skip_glued, projectile: def.projectile.clone(), buff: def.buff.clone(), attack_fn_name, attack_fn, attack_at: 0, state: def.state.clone() };
+    }
+}
+
+/// The state of a tower, without its prototype. Function pointers can't be saved, so the prototype is rebuilt from type and upgrades.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TowerSave {
+    pub tower_type: TowerType,
+    pub upgrades: Upgradable,
+    pub cur_targeting_mode: usize,
+    pub buffs: Vec<TowerBuff>,
+    pub spent: i64,
+    pub attacks: Vec<AttackSave>, // in prototype order
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AttackSave {
+    pub attack_at: usize,
+    pub state: AttackState,
+}
+
+impl Tower {
+    pub fn to_save(&self)->TowerSave {
+        return TowerSave {
+            tower_type: self.tower_type,
+            upgrades: self.upgrades,
+            cur_targeting_mode: self.cur_targeting_mode,
+            buffs: self.buffs.clone(),
+            spent: self.spent,
+            attacks: self.attacks.iter().map(|a| AttackSave { attack_at: a.attack_at, state: a.state.clone() }).collect(),
+        };
+    }
+    pub fn from_save(defs: &TowerDefinitions, save: &TowerSave)->Self {
+        let mut tower = Tower::from(defs, save.tower_type, save.upgrades);
+        tower.set_targeting_mode(save.cur_targeting_mode);
+        tower.buffs = save.buffs.clone();
+        tower.buff_stats = BuffStats::from_buffs(&tower.buffs);
+        tower.spent = save.spent;
+        for (attack, saved) in tower.attacks.iter_mut().zip(&save.attacks) {
+            attack.attack_at = saved.attack_at;
+            attack.state = saved.state.clone();
+        }
+        return tower;
+    }
+}
+
+/// Stable id of a tower, so that replays (and anything else outliving an `Entity`) can refer to it
+#[derive(Component, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct TowerId(pub u32);
+
+/*
+    Events
+*/
+
+#[derive(Event)]
+/// A tower was upgraded (for the UI)
+pub struct TowerUpgradedEvent {
+    pub tower: Entity,
+    pub id: TowerId,
+    pub path: u8,
+    pub upgrades: Upgradable,
+}
+
+#[derive(Event)]
+/// A tower was sold (for the UI)
+pub struct TowerSoldEvent {
+    pub id: TowerId,
+    pub refund: i64,
 }
 
 /*
     Systems
 */
 
-pub fn towers_tick(mut towers: Query<(&mut Tower, &Transform, &GlobalTransform)>) {
-    for (mut tower, pos, gpos) in &mut towers {
+/// Apply tower placement, targeting, upgrade and sell commands
+pub fn apply_tower_commands(mut cmd: Commands, mut commands: EventReader<PlayerCommand>, defs: Res<TowerDefinitions>, mut game: ResMut<GameState>, mut towers: Query<(Entity, &TowerId, &mut Tower), Without<Subtower>>, children: Query<&Children>, mut subtowers: Query<(&mut Subtower, &Tower)>, heroes: Query<(), With<Hero>>, mut upgraded_ew: EventWriter<TowerUpgradedEvent>, mut sold_ew: EventWriter<TowerSoldEvent>) {
+    let mut has_hero = !heroes.is_empty();
+    // the despawn of a sold tower is deferred, so it's still in the query for the rest of the tick
+    let mut sold: HashSet<TowerId> = HashSet::new();
+    for command in commands.read() {
+        match command {
+            PlayerCommand::PlaceTower { tower_type, pos } => {
+                if tower_type.is_subtower() { continue; }
+                let Some(def) = defs.get(tower_type) else { continue; };
+                if game.cash < def.cost { continue; }
+                let is_hero = def.hero.is_some();
+                if is_hero && has_hero { continue; } // one hero per game
+                game.cash -= def.cost;
+                let id = TowerId(game.next_tower_id);
+                game.next_tower_id += 1;
+                let upgrades = if is_hero { Upgradable::Hero20(1) } else { Upgradable::Crosspath520(0,0,0) };
+                let mut tower = Tower::from(&*defs, *tower_type, upgrades);
+                tower.spent = def.cost;
+                let subtowers = tower.get_subtowers().to_vec();
+                let mut e = cmd.spawn((tower, id, Transform::from_xyz(pos.x, pos.y, 2.), get_tower_sprite()));
+                if is_hero {
+                    e.insert(Hero::default());
+                    has_hero = true;
+                }
+                let e = e.id();
+                spawn_subtowers(&mut cmd, &defs, &mut game, e, &subtowers);
+            },
+            PlayerCommand::SetTargeting { tower, mode } => {
+                if sold.contains(tower) { continue; }
+                if let Some((_, _, mut t)) = towers.iter_mut().find(|(_, id, _)| **id == *tower) {
+                    t.set_targeting_mode(*mode);
+                }
+            },
+            PlayerCommand::UpgradeTower { tower, path } => {
+                if sold.contains(tower) { continue; }
+                let Some((e, id, mut t)) = towers.iter_mut().find(|(_, id, _)| **id == *tower) else { continue; };
+                let Some(upgrades) = t.get_upgrades().upgraded(*path) else { continue; };
+                let Some(cost) = defs.get(&t.get_tower_type()).and_then(|def| def.upgrade_cost(*path, upgrades.path_tier(*path))) else { continue; };
+                if game.cash < cost { continue; }
+                game.cash -= cost;
+                t.upgrade(&*defs, upgrades);
+                t.spent += cost;
+                // the new prototype may come with other subtowers
+                update_subtowers(&mut cmd, &defs, &mut game, e, children.get(e).ok(), &mut subtowers, t.get_subtowers());
+                upgraded_ew.send(TowerUpgradedEvent { tower: e, id: *id, path: *path, upgrades });
+            },
+            PlayerCommand::SellTower { tower } => {
+                if !sold.insert(*tower) { continue; }
+                let Some((e, _, t)) = towers.iter().find(|(_, id, _)| **id == *tower) else { continue; };
+                let refund = t.spent * game.sell_percent / 100;
+                game.cash += refund;
+                // subtowers are children of their tower, so they go with it
+                cmd.entity(e).despawn_recursive();
+                for (_, _, mut other) in &mut towers {
+                    other.remove_buffs_from(*tower);
+                }
+                sold_ew.send(TowerSoldEvent { id: *tower, refund });
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Recompute the effective stats of towers whose buffs or prototype changed
+pub fn update_effective_stats(mut towers: Query<(&Tower, &mut EffectiveAttackStats)>) {
+    for (tower, mut stats) in &mut towers {
+        if stats.revision == Some(tower.stats_revision) { continue; }
+        stats.attacks = tower.attacks.iter().map(|a| AttackStats::from(a, &tower.buff_stats)).collect();
+        stats.revision = Some(tower.stats_revision);
+    }
+}
+
+/// Pick the bloon an attack shoots at: one it can see and reach, chosen by the targeting mode. Ties go to the lowest bloon id so that it's deterministic.
+fn find_bloon_target(mode: TargetingMode, stats: &AttackStats, tower_pos: Vec2, map: &Map, bloons: &Query<(Entity, &Bloon, &MoveAlongRoad, &Transform)>)->Option<(Entity, Vec2)> {
+    let mut best: Option<(f32, (u32, u8, u32), Entity, Vec2)> = None;
+    for (e, bloon, re, pos) in bloons {
+        let pos = pos.translation.truncate();
+        let dist = pos.distance(tower_pos);
+        if dist > stats.range { continue; }
+        if bloon.modifiers & BloonModifierData::Camo as BloonModifier & !stats.detection != 0 { continue; }
+        if stats.skip_glued && bloon.is_glued() { continue; }
+        if stats.height.is_some_and(|h| !map.line_of_sight(tower_pos, pos, h)) { continue; }
+        // higher is better
+        let score = match mode {
+            TargetingMode::LastBloon => -re.road_pos,
+            TargetingMode::StrongBloon => bloon.tier as u8 as f32 * 1e6 + re.road_pos,
+            TargetingMode::CloseBloon => -dist,
+            _ => re.road_pos,
+        };
+        let id = (bloon.bid.family, bloon.bid.layer, bloon.bid.tree);
+        if best.as_ref().is_none_or(|(s, bid, _, _)| score > *s || (score == *s && id < *bid)) {
+            best = Some((score, id, e, pos));
+        }
+    }
+    return best.map(|(_, _, e, pos)| (e, pos));
+}
+
+/// A tower as seen by tower targeted attacks
+struct TowerSnapshot {
+    entity: Entity,
+    id: TowerId,
+    pos: Vec2,
+    buff_kinds: Vec<BuffKind>,
+}
+
+/// Fire every attack whose cooldown is over and that has something to shoot at
+pub fn tower_attacks(mut cmd: Commands, tick: Res<SimTick>, map: Res<Map>, game: Res<GameState>, mut towers: Query<(Entity, &TowerId, &mut Tower, &EffectiveAttackStats, &Transform, Option<&Parent>)>, parents: Query<&Transform, (With<Tower>, Without<Parent>)>, bloons: Query<(Entity, &Bloon, &MoveAlongRoad, &Transform)>) {
+    // subtowers get their buffs from their parent, so they aren't targeted
+    let snapshot: Vec<TowerSnapshot> = towers.iter().filter(|(.., parent)| parent.is_none()).map(|(entity, id, tower, _, pos, _)| TowerSnapshot {
+        entity, id: *id, pos: pos.translation.truncate(), buff_kinds: tower.buffs.iter().map(|b| b.kind).collect(),
+    }).collect();
+    // fire in id order so projectiles are spawned (and numbered) the same way every run
+    let mut order: Vec<(TowerId, Entity)> = towers.iter().map(|(e, id, ..)| (*id, e)).collect();
+    order.sort_unstable();
+    for (_, e) in order {
+        let Ok((_, id, mut tower, stats, pos, parent)) = towers.get_mut(e) else { continue; };
         let targeting_mode = tower.get_targeting_mode();
-        for mut attack in &mut tower.attacks {
-            gpos.translation();
-            // match attack {
-            //     Attack::Single { range, cooldown, attack_rate, target_angle, attack_fn } => {
-            //         *cooldown -= 1;
-            //         if *cooldown <= 0 {
-            //             target_angle
-            //         }
-            //     },
-            //     _ => {}
-            // }
+        let tower_pos = tower_world_pos(pos, parent, &parents);
+        // other towers in range of an attack, closest first
+        let towers_in_range = |range: f32| {
+            let mut in_range: Vec<&TowerSnapshot> = snapshot.iter().filter(|t| t.id != *id && t.pos.distance(tower_pos) <= range).collect();
+            in_range.sort_by(|a, b| a.pos.distance(tower_pos).total_cmp(&b.pos.distance(tower_pos)).then(a.id.cmp(&b.id)));
+            in_range
+        };
+        for (attack, stats) in tower.attacks.iter_mut().zip(&stats.attacks) {
+            if tick.0 < attack.attack_at { continue; }
+            let target = match &mut attack.kind {
+                AttackKind::TargetEntity { target_angle, target_entity } => {
+                    let Some((e, bloon_pos)) = find_bloon_target(targeting_mode, stats, tower_pos, &map, &bloons) else { continue; };
+                    let angle = (bloon_pos - tower_pos).to_angle();
+                    (*target_angle, *target_entity) = (Some(angle), Some(e));
+                    AttackTarget::Bloon { angle, entity: e, pos: bloon_pos }
+                },
+                AttackKind::ArcSpread { target_angle } => {
+                    let Some((_, bloon_pos)) = find_bloon_target(targeting_mode, stats, tower_pos, &map, &bloons) else { continue; };
+                    let angle = (bloon_pos - tower_pos).to_angle();
+                    *target_angle = Some(angle);
+                    AttackTarget::Angle(angle)
+                },
+                AttackKind::TargetRoad { target_waypoint } => {
+                    if !game.round_active || map.path.is_empty() { continue; }
+                    let waypoint = map.closest_pos(tower_pos);
+                    if waypoint.distance(tower_pos) > stats.range { continue; }
+                    *target_waypoint = Some(waypoint);
+                    AttackTarget::Road(waypoint)
+                },
+                AttackKind::Circle => {
+                    if !game.round_active { continue; }
+                    AttackTarget::None
+                },
+                AttackKind::Teleport { target_point } => {
+                    let Some((_, bloon_pos)) = find_bloon_target(targeting_mode, stats, tower_pos, &map, &bloons) else { continue; };
+                    *target_point = Some(bloon_pos);
+                    AttackTarget::Point(bloon_pos)
+                },
+                AttackKind::TargetTower { target_tower } => {
+                    let Some(buff) = &stats.buff else { continue; };
+                    let Some(t) = towers_in_range(stats.range).into_iter().find(|t| !t.buff_kinds.contains(&buff.kind)) else { continue; };
+                    *target_tower = Some(t.entity);
+                    AttackTarget::Tower { entity: t.entity, pos: t.pos, source: *id }
+                },
+                AttackKind::RangeBuff => {
+                    let entities: Vec<Entity> = towers_in_range(stats.range).iter().map(|t| t.entity).collect();
+                    if entities.is_empty() { continue; }
+                    AttackTarget::Towers { entities, source: *id }
+                },
+            };
+            (attack.attack_fn)(&mut cmd, &target, stats, &mut attack.state, tower_pos.extend(pos.translation.z));
+            attack.attack_at = tick.0 + attack.state.next_delay().unwrap_or(stats.cooldown).max(1) as usize;
         }
     }
-}
\ No newline at end of file
+}
+// TODO: remove from here
+pub fn get_tower_sprite()->Sprite {
+    return Sprite::from_color(Color::srgb(0.6, 0.4, 0.2), Vec2::new(40., 40.));
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{ecs::system::RunSystemOnce, math::vec2};
+
+    use crate::core::{bloon::BloonTier, buff::{BuffStacking, BuffScope}, command::PendingPlayerCommands, replay::{headless_app, step_headless}};
+
+    use super::*;
+
+    fn target_from(world: &mut World, tower_pos: Vec2, height: Option<f32>)->Option<Entity> {
+        let projectile: ProjectileStats = ron::from_str("(damage: 1, pierce: 1, speed: 5.0, radius: 5.0, lifetime: 20, damage_type: Normal)").unwrap();
+        let stats = AttackStats { range: 200., height, ..AttackStats::from_projectile(&projectile) };
+        return world.run_system_once(move |map: Res<Map>, bloons: Query<(Entity, &Bloon, &MoveAlongRoad, &Transform)>| {
+            return find_bloon_target(TargetingMode::FirstBloon, &stats, tower_pos, &map, &bloons).map(|(e, _)| e);
+        }).unwrap();
+    }
+
+    fn queue(app: &mut App, command: PlayerCommand) {
+        app.world_mut().resource_mut::<PendingPlayerCommands>().push(command);
+        step_headless(app);
+    }
+
+    fn only_tower(app: &mut App)->(&Tower, &Abilities) {
+        let world = app.world_mut();
+        return world.query::<(&Tower, &Abilities)>().single(world);
+    }
+
+    #[test]
+    fn upgrade_keeps_buffs_targeting_and_cooldowns() {
+        let mut app = headless_app(0, 1, false);
+        step_headless(&mut app);
+        app.world_mut().resource_mut::<GameState>().cash = 100_000;
+        queue(&mut app, PlayerCommand::PlaceTower { tower_type: TowerType::DartMonkey, pos: vec2(-150., 0.) });
+        for _ in 0..4 { queue(&mut app, PlayerCommand::UpgradeTower { tower: TowerId(0), path: 2 }); }
+        queue(&mut app, PlayerCommand::SetTargeting { tower: TowerId(0), mode: 2 });
+        {
+            let world = app.world_mut();
+            let (mut tower, mut abilities) = world.query::<(&mut Tower, &mut Abilities)>().single_mut(world);
+            tower.add_buff(overclock(7, 0.5));
+            tower.attacks[0].attack_at = 1000;
+            abilities.abilities[0].cooldown_left = 500;
+        }
+
+        queue(&mut app, PlayerCommand::UpgradeTower { tower: TowerId(0), path: 1 });
+        let (tower, abilities) = only_tower(&mut app);
+        assert!(tower.get_upgrades() == Upgradable::Crosspath520(1, 4, 0));
+        assert_eq!(tower.cur_targeting_mode(), 2);
+        assert!(tower.get_buffs().iter().any(|b| b.source == Some(TowerId(7))));
+        assert_eq!(tower.get_buff_stats().attack_rate_mult, 0.5);
+        assert_eq!(tower.attacks[0].attack_at, 1000);
+        assert_eq!(abilities.abilities[0].cooldown_left, 500);
+    }
+
+    /// A Triple Shot dart monkey in the middle of a burst
+    fn mid_burst(defs: &TowerDefinitions)->Tower {
+        let mut tower = Tower::from(defs, TowerType::DartMonkey, Upgradable::Crosspath520(0, 3, 0));
+        tower.attacks[0].state = AttackState::Burst { queued: 2, interval: 4 };
+        tower.attacks[0].attack_at = 123;
+        return tower;
+    }
+
+    fn assert_mid_burst(tower: &Tower) {
+        assert!(matches!(tower.attacks[0].state, AttackState::Burst { queued: 2, interval: 4 }));
+        assert_eq!(tower.attacks[0].attack_at, 123);
+    }
+
+    #[test]
+    fn attack_state_survives_upgrade() {
+        let defs = TowerDefinitions::load_embedded();
+        let mut tower = mid_burst(&defs);
+        tower.upgrade(&defs, Upgradable::Crosspath520(0, 3, 1));
+        assert_mid_burst(&tower);
+    }
+
+    #[test]
+    fn attack_state_survives_save() {
+        let defs = TowerDefinitions::load_embedded();
+        let text = ron::to_string(&mid_burst(&defs).to_save()).unwrap();
+        assert_mid_burst(&Tower::from_save(&defs, &ron::from_str(&text).unwrap()));
+    }
+
+    #[test]
+    fn obstacles_block_targeting() {
+        let mut world = World::new();
+        world.insert_resource(Map::get_map(1));
+        let re = MoveAlongRoad { target_node: 1, road_pos: 0., waypoint: Vec2::ZERO, velocity: 1., reversed: false };
+        let bloon = world.spawn((Bloon::with(BloonTier::Red, 0), re, Transform::from_xyz(0., 0., 1.))).id();
+        // the rock (height 20) is between (-100, 0) and the bloon
+        assert_eq!(target_from(&mut world, vec2(-100., 0.), Some(10.)), None);
+        assert_eq!(target_from(&mut world, vec2(-100., 0.), Some(25.)), Some(bloon));
+        assert_eq!(target_from(&mut world, vec2(-100., 0.), None), Some(bloon));
+        assert_eq!(target_from(&mut world, vec2(0., -100.), Some(10.)), Some(bloon));
+    }
+
+    fn overclock(source: u32, strength: f32)->TowerBuff {
+        return TowerBuff { source: Some(TowerId(source)), kind: BuffKind::FanClub, scope: BuffScope::Single, stacking: BuffStacking::Override, duration: BuffDuration::Ticks(100), effect: TowerEffect::AttackRate { strength } };
+    }
+
+    #[test]
+    fn latest_override_counts() {
+        let mut tower = Tower::zero();
+        tower.add_buff(overclock(1, 0.5));
+        tower.add_buff(overclock(2, 0.8));
+        assert_eq!(tower.get_buff_stats().attack_rate_mult, 0.8);
+    }
+
+    #[test]
+    fn refreshed_override_counts() {
+        let mut tower = Tower::zero();
+        tower.add_buff(overclock(1, 0.5));
+        tower.add_buff(overclock(2, 0.8));
+        tower.add_buff(overclock(1, 0.5));
+        assert_eq!(tower.get_buffs().len(), 2);
+        assert_eq!(tower.get_buff_stats().attack_rate_mult, 0.5);
+    }
+}
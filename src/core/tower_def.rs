@@ -0,0 +1,390 @@
+use bevy::{prelude::*, utils::HashMap};
+use serde::{Deserialize, Serialize};
+
+use crate::core::{ability::{get_ability_fn, AbilityDef}, attack_fn::{get_attack_fn, AttackState}, bloon::{BloonEffect, DamageType}, buff::{BuffAura, BuffDef, BuffScope}, hero::HeroDef, subtower::SubtowerDef, tower::{TargetingMode, TowerType}, upgradable::Upgradable};
+
+/// Tower data files, one per tower type. Embedded so that the simulation never depends on the working directory.
+const TOWER_DATA: &[(&str, &str)] = &[
+    ("dart_monkey.ron", include_str!("../../assets/towers/dart_monkey.ron")),
+    ("tack_shooter.ron", include_str!("../../assets/towers/tack_shooter.ron")),
+    ("mortar_monkey.ron", include_str!("../../assets/towers/mortar_monkey.ron")),
+    ("alch_monkey.ron", include_str!("../../assets/towers/alch_monkey.ron")),
+    ("village.ron", include_str!("../../assets/towers/village.ron")),
+    ("heli_monkey.ron", include_str!("../../assets/towers/heli_monkey.ron")),
+    ("heli_subtower.ron", include_str!("../../assets/towers/heli_subtower.ron")),
+    ("ace_monkey.ron", include_str!("../../assets/towers/ace_monkey.ron")),
+    ("ace_subtower.ron", include_str!("../../assets/towers/ace_subtower.ron")),
+    ("spike_factory.ron", include_str!("../../assets/towers/spike_factory.ron")),
+    ("banana_farm.ron", include_str!("../../assets/towers/banana_farm.ron")),
+    ("quincy.ron", include_str!("../../assets/towers/quincy.ron")),
+    ("ice_monkey.ron", include_str!("../../assets/towers/ice_monkey.ron")),
+    ("glue_gunner.ron", include_str!("../../assets/towers/glue_gunner.ron")),
+];
+
+/*
+    Definitions
+*/
+
+/// Stats of the projectiles an attack spawns
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ProjectileStats {
+    pub damage: i32,
+    pub pierce: u32,
+    pub speed: f32,
+    pub radius: f32,
+    pub lifetime: i32, // ticks
+    #[serde(default)]
+    pub lifetime_rounds: Option<i32>, // projectiles that stay on the track also disappear after this many round ends
+    #[serde(default)]
+    pub cash: i64, // for collectibles (bananas)
+    #[serde(default)]
+    pub height: Option<f32>, // obstacles taller than this block line of sight and stop the projectiles; None flies over everything
+    #[serde(default)]
+    pub bounce: Option<BounceStats>,
+    #[serde(default)]
+    pub seek: Option<SeekStats>,
+    #[serde(default)]
+    pub blowback: f32, // road distance the bloons it hits are pushed back
+    #[serde(default)]
+    pub status: Option<StatusStats>,
+    pub damage_type: DamageType,
+    #[serde(default = "one")]
+    pub multishot: u32, // projectiles per shot
+    #[serde(default)]
+    pub spread: f32, // total angle (radians) the projectiles of one shot are fanned over
+}
+
+/// How projectiles bounce off walls and obstacles (see `movement::Bounce`); ricochets (`SeekStats::ricochet`) use up the same `count`
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct BounceStats {
+    pub count: i32,
+    #[serde(default)]
+    pub clear_hits: bool,
+}
+
+/// How projectiles turn to another bloon after a hit (see `movement::SeekAfterHitMove`)
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SeekStats {
+    pub range: Option<f32>, // None for any distance
+    pub ricochet: bool, // every turn uses up one of the projectile's bounces (see `BounceStats::count`); otherwise it turns after every hit
+    pub clear_hits: bool,
+}
+
+/// A status effect the projectiles put on the bloons they damage
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum StatusStats {
+    Freeze { duration: i32 }, // stopped, and immune to sharp until it thaws
+    Glue {
+        duration: i32,
+        slow: f32, // speed multiplier
+        #[serde(default)]
+        dot: Option<(i32, u32)>, // (damage, every this many ticks)
+        #[serde(default)]
+        layers: u8, // how many layers of children keep the glue
+    },
+    Decamo,
+    Delead,
+    Deregrow,
+}
+
+impl StatusStats {
+    /// The bloon effects this status is made of
+    pub fn effects(&self)->Vec<BloonEffect> {
+        return match *self {
+            StatusStats::Freeze { duration } => vec![BloonEffect::Frozen { duration: Some(duration) }],
+            StatusStats::Glue { duration, slow, dot, layers } => vec![BloonEffect::Glued { duration: Some(duration), slow, dot, layers }],
+            StatusStats::Decamo => vec![BloonEffect::Decamo],
+            StatusStats::Delead => vec![BloonEffect::Delead],
+            StatusStats::Deregrow => vec![BloonEffect::Deregrow],
+        };
+    }
+}
+
+fn one()->u32 {
+    return 1;
+}
+
+/// How an attack picks its target (see `tower::AttackKind`)
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum AttackKindDef {
+    TargetEntity,
+    TargetRoad,
+    ArcSpread,
+    Circle,
+    Teleport,
+    TargetTower,
+    RangeBuff,
+}
+
+/// One attack of a tower prototype
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AttackDef {
+    pub kind: AttackKindDef,
+    pub range: f32,
+    pub attack_rate: i32, // ticks between shots
+    pub attack_fn: String, // name in the attack function registry (see `attack_fn::get_attack_fn`)
+    #[serde(default)]
+    pub camo_detection: bool,
+    #[serde(default)]
+    pub skip_glued: bool, // don't target bloons that are already glued
+    pub projectile: ProjectileStats,
+    #[serde(default)]
+    pub state: AttackState, // initial state for the attack function
+    #[serde(default)]
+    pub buff: Option<BuffDef>, // for TargetTower and RangeBuff attacks
+}
+
+/// What a single upgrade changes. Stat changes apply to every attack the tower has at that point.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UpgradeDef {
+    pub name: String,
+    pub cost: i64,
+    pub range: f32, // added, to attacks and area auras
+    pub attack_rate_mult: Option<f32>,
+    pub damage: i32, // added
+    pub pierce: i32, // added
+    pub speed_mult: Option<f32>,
+    pub radius_mult: Option<f32>,
+    pub lifetime: i32, // added
+    pub lifetime_rounds: Option<i32>,
+    pub cash: i64, // added
+    pub bounce: Option<BounceStats>, // replaces how the projectiles bounce
+    pub seek: Option<SeekStats>, // replaces how the projectiles seek
+    pub blowback: f32, // added
+    pub status: Option<StatusStats>, // replaces the status effect
+    pub multishot: Option<u32>,
+    pub spread: Option<f32>,
+    pub damage_type: Option<DamageType>,
+    pub camo_detection: bool, // only ever grants it
+    pub attack_fn: Option<String>, // replaces the attack function
+    pub added_attacks: Vec<AttackDef>,
+    pub added_auras: Vec<BuffAura>,
+    pub added_subtowers: Vec<SubtowerDef>,
+    pub added_abilities: Vec<AbilityDef>,
+    pub targeting_modes: Option<Vec<TargetingMode>>, // replaces the targeting modes
+}
+
+/// A tower type's base prototype and all its upgrades
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TowerDef {
+    pub tower_type: TowerType,
+    pub cost: i64,
+    pub targeting_modes: Vec<TargetingMode>,
+    pub attacks: Vec<AttackDef>,
+    #[serde(default)]
+    pub auras: Vec<BuffAura>,
+    #[serde(default)]
+    pub subtowers: Vec<SubtowerDef>,
+    #[serde(default)]
+    pub abilities: Vec<AbilityDef>,
+    #[serde(default)]
+    pub hero: Option<HeroDef>, // heroes level up with XP instead of having upgrade paths
+    #[serde(default)]
+    pub paths: [Vec<UpgradeDef>; 3], // up to 5 upgrades each for `Upgradable::Crosspath520` towers
+}
+
+/// A tower prototype with every upgrade merged in
+pub struct ResolvedTowerDef {
+    pub attacks: Vec<AttackDef>,
+    pub auras: Vec<BuffAura>,
+    pub subtowers: Vec<SubtowerDef>,
+    pub abilities: Vec<AbilityDef>,
+    pub targeting_modes: Vec<TargetingMode>,
+}
+
+impl UpgradeDef {
+    /// Add this upgrade's attacks, auras, subtowers and abilities
+    fn add_to(&self, tower: &mut ResolvedTowerDef) {
+        tower.attacks.extend(self.added_attacks.iter().cloned());
+        tower.auras.extend(self.added_auras.iter().cloned());
+        tower.subtowers.extend(self.added_subtowers.iter().cloned());
+        tower.abilities.extend(self.added_abilities.iter().cloned());
+    }
+    /// Apply this upgrade's stat changes to every attack and aura
+    fn apply(&self, tower: &mut ResolvedTowerDef) {
+        for attack in &mut tower.attacks {
+            attack.range += self.range;
+            if let Some(mult) = self.attack_rate_mult { attack.attack_rate = ((attack.attack_rate as f32 * mult).round() as i32).max(1); }
+            if let Some(f) = &self.attack_fn { attack.attack_fn = f.clone(); }
+            attack.camo_detection |= self.camo_detection;
+            let p = &mut attack.projectile;
+            p.damage += self.damage;
+            p.pierce = (p.pierce as i32 + self.pierce).max(1) as u32;
+            p.lifetime += self.lifetime;
+            if let Some(rounds) = self.lifetime_rounds { p.lifetime_rounds = Some(rounds); }
+            p.cash += self.cash;
+            if let Some(bounce) = self.bounce { p.bounce = Some(bounce); }
+            if let Some(seek) = self.seek { p.seek = Some(seek); }
+            p.blowback += self.blowback;
+            if let Some(status) = self.status { p.status = Some(status); }
+            if let Some(mult) = self.speed_mult { p.speed *= mult; }
+            if let Some(mult) = self.radius_mult { p.radius *= mult; }
+            if let Some(multishot) = self.multishot { p.multishot = multishot; }
+            if let Some(spread) = self.spread { p.spread = spread; }
+            if let Some(damage_type) = self.damage_type { p.damage_type = damage_type; }
+        }
+        for aura in &mut tower.auras {
+            if aura.scope == BuffScope::Area { aura.range += self.range; }
+        }
+        if let Some(modes) = &self.targeting_modes { tower.targeting_modes = modes.clone(); }
+    }
+}
+
+impl TowerDef {
+    /// Merge the base prototype with every upgrade bought (path 1 first, then 2, then 3), or every hero level reached.
+    /// Everything the upgrades add comes first, so that every stat change reaches every attack and aura, whichever path added it.
+    pub fn resolve(&self, upgrades: Upgradable)->ResolvedTowerDef {
+        let mut tower = ResolvedTowerDef { attacks: self.attacks.clone(), auras: self.auras.clone(), subtowers: self.subtowers.clone(), abilities: self.abilities.clone(), targeting_modes: self.targeting_modes.clone() };
+        let mut bought: Vec<&UpgradeDef> = vec![];
+        for path in 1..=3 {
+            bought.extend(self.paths[path as usize - 1].iter().take(upgrades.path_tier(path) as usize));
+        }
+        if let (Upgradable::Hero20(level), Some(hero)) = (upgrades, &self.hero) {
+            bought.extend(hero.levels.iter().take(level.saturating_sub(1) as usize));
+        }
+        for upgrade in &bought { upgrade.add_to(&mut tower); }
+        for upgrade in &bought { upgrade.apply(&mut tower); }
+        return tower;
+    }
+    /// Cost of upgrade number `tier` (1-5) of path `path` (1, 2 or 3). None if there's no such upgrade.
+    pub fn upgrade_cost(&self, path: u8, tier: u8)->Option<i64> {
+        let upgrades = self.paths.get((path as usize).wrapping_sub(1))?;
+        return upgrades.get((tier as usize).wrapping_sub(1)).map(|u| u.cost);
+    }
+    /// Every prototype this tower can turn into (every hero level, or every combination of path tiers, allowed crosspath or not)
+    fn every_resolved(&self)->Vec<ResolvedTowerDef> {
+        if let Some(hero) = &self.hero {
+            return (1..=hero.levels.len() as u8 + 1).map(|level| self.resolve(Upgradable::Hero20(level))).collect();
+        }
+        let mut resolved = vec![];
+        for a in 0..=self.paths[0].len() as u8 {
+            for b in 0..=self.paths[1].len() as u8 {
+                for c in 0..=self.paths[2].len() as u8 {
+                    resolved.push(self.resolve(Upgradable::Crosspath520(a, b, c)));
+                }
+            }
+        }
+        return resolved;
+    }
+    /// Panic on anything in this definition that would only go wrong in game
+    fn validate(&self, file: &str) {
+        for name in self.attack_fn_names() {
+            if get_attack_fn(name).is_none() { panic!("Unknown attack function \"{name}\" in {file}"); }
+        }
+        for name in self.ability_fn_names() {
+            if get_ability_fn(name).is_none() { panic!("Unknown ability effect \"{name}\" in {file}"); }
+        }
+        // bursts keep their count in the attack state; without one, they'd fire single shots
+        for tower in self.every_resolved() {
+            for attack in &tower.attacks {
+                if attack.attack_fn == "burst" && !matches!(attack.state, AttackState::Burst { .. }) { panic!("Attack function \"burst\" without a Burst state in {file}"); }
+            }
+        }
+    }
+    fn upgrade_defs(&self)->impl Iterator<Item = &UpgradeDef> {
+        return self.paths.iter().flatten().chain(self.hero.iter().flat_map(|h| &h.levels));
+    }
+    /// Every attack function name used anywhere in this definition
+    fn attack_fn_names(&self)->Vec<&str> {
+        let mut names: Vec<&str> = self.attacks.iter().map(|a| a.attack_fn.as_str()).collect();
+        for upgrade in self.upgrade_defs() {
+            names.extend(upgrade.attack_fn.as_deref());
+            names.extend(upgrade.added_attacks.iter().map(|a| a.attack_fn.as_str()));
+        }
+        return names;
+    }
+    /// Every ability effect name used anywhere in this definition
+    fn ability_fn_names(&self)->Vec<&str> {
+        let mut names: Vec<&str> = self.abilities.iter().map(|a| a.effect.as_str()).collect();
+        for upgrade in self.upgrade_defs() {
+            names.extend(upgrade.added_abilities.iter().map(|a| a.effect.as_str()));
+        }
+        return names;
+    }
+}
+
+/// Definitions of every tower type that has one. Towers without a definition can't be placed.
+#[derive(Resource, Deref)]
+pub struct TowerDefinitions(pub HashMap<TowerType, TowerDef>);
+
+impl TowerDefinitions {
+    /// Parse the embedded tower data. Broken data is a bug, not something to recover from, so this panics on it.
+    pub fn load_embedded()->Self {
+        let mut defs = HashMap::default();
+        for (file, data) in TOWER_DATA {
+            let def: TowerDef = ron::from_str(data).unwrap_or_else(|e| panic!("Invalid tower data in {file}: {e}"));
+            def.validate(file);
+            defs.insert(def.tower_type, def);
+        }
+        return TowerDefinitions(defs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embedded_definitions_load() {
+        let defs = TowerDefinitions::load_embedded();
+        assert_eq!(defs.len(), TOWER_DATA.len());
+    }
+
+    #[test]
+    fn range_reaches_every_aura() {
+        let defs = TowerDefinitions::load_embedded();
+        for (upgrades, auras) in [(Upgradable::Crosspath520(1, 2, 0), 2), (Upgradable::Crosspath520(2, 2, 0), 3)] {
+            let village = defs[&TowerType::Village].resolve(upgrades);
+            assert_eq!(village.auras.len(), auras);
+            assert!(village.auras.iter().all(|aura| aura.range == 144.0));
+            assert_eq!(village.attacks.len(), 1);
+            assert_eq!(village.attacks[0].range, 144.0);
+        }
+    }
+
+    const CROSSPATH: &str = r#"(
+        tower_type: DartMonkey,
+        cost: 100,
+        targeting_modes: [FirstBloon],
+        attacks: [(kind: TargetEntity, range: 100.0, attack_rate: 60, attack_fn: "single",
+            projectile: (damage: 1, pierce: 1, speed: 10.0, radius: 5.0, lifetime: 20, damage_type: Normal))],
+        paths: (
+            [(name: "Sharper", cost: 100, range: 10.0, damage: 1, pierce: 2)],
+            [],
+            [(name: "Second attack", cost: 100, added_attacks: [(kind: TargetEntity, range: 50.0, attack_rate: 30, attack_fn: "single",
+                projectile: (damage: 2, pierce: 1, speed: 10.0, radius: 5.0, lifetime: 20, damage_type: Normal))])],
+        ),
+    )"#;
+
+    #[test]
+    fn crosspath_stats_reach_later_attacks() {
+        let def: TowerDef = ron::from_str(CROSSPATH).unwrap();
+        let tower = def.resolve(Upgradable::Crosspath520(1, 0, 1));
+        let stats: Vec<(f32, i32, u32)> = tower.attacks.iter().map(|a| (a.range, a.projectile.damage, a.projectile.pierce)).collect();
+        assert_eq!(stats, vec![(110.0, 2, 3), (60.0, 3, 3)]);
+    }
+
+    const BURST: &str = r#"(
+        tower_type: DartMonkey,
+        cost: 100,
+        targeting_modes: [FirstBloon],
+        attacks: [(kind: TargetEntity, range: 100.0, attack_rate: 60, attack_fn: "single", state: Burst(queued: 0, interval: 4),
+            projectile: (damage: 1, pierce: 1, speed: 10.0, radius: 5.0, lifetime: 20, damage_type: Normal, multishot: 3))],
+        paths: ([(name: "Bursts", cost: 100, attack_fn: Some("burst"))], [(name: "Single", cost: 100, attack_fn: Some("single"))], []),
+    )"#;
+
+    #[test]
+    fn burst_with_burst_state() {
+        let def: TowerDef = ron::from_str(BURST).unwrap();
+        def.validate("test");
+    }
+
+    #[test]
+    #[should_panic(expected = "without a Burst state")]
+    fn burst_without_burst_state() {
+        let def: TowerDef = ron::from_str(&BURST.replace("state: Burst(queued: 0, interval: 4),", "")).unwrap();
+        def.validate("test");
+    }
+}
@@ -1,6 +1,6 @@
-use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Eq, Clone, Copy, Hash, Serialize, Deserialize)]
 pub enum Upgradable {
     Crosspath520(u8,u8,u8),
     None,
@@ -28,6 +28,34 @@ impl Upgradable {
         }
         return false;
     }
+    /// Return true if path `path` (1, 2 or 3) is upgradable
+    pub fn can_upgrade(&self, path: u8)->bool {
+        return match path {
+            1 => self.can_upgrade_1(),
+            2 => self.can_upgrade_2(),
+            3 => self.can_upgrade_3(),
+            _ => false,
+        };
+    }
+    /// Return the upgrades after buying the next upgrade of path `path` (1, 2 or 3), if it can be bought
+    pub fn upgraded(&self, path: u8)->Option<Upgradable> {
+        if !self.can_upgrade(path) { return None; }
+        if let Upgradable::Crosspath520(p1, p2, p3) = *self {
+            return Some(match path {
+                1 => Upgradable::Crosspath520(p1+1, p2, p3),
+                2 => Upgradable::Crosspath520(p1, p2+1, p3),
+                _ => Upgradable::Crosspath520(p1, p2, p3+1),
+            });
+        }
+        return None;
+    }
+    /// Return the tier (1-5) of path `path` (1, 2 or 3)
+    pub fn path_tier(&self, path: u8)->u8 {
+        if let Upgradable::Crosspath520(p1, p2, p3) = *self {
+            return match path { 1 => p1, 2 => p2, 3 => p3, _ => 0 };
+        }
+        return 0;
+    }
     /// Return true if path 3 is upgradable
     pub fn can_upgrade_3(&self)->bool {
         if let Upgradable::Crosspath520(p1, p2, p3) = *self {
@@ -0,0 +1,143 @@
+use bevy::{math::ops::hypot, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::core::{bloon::{Bloon, BloonTier}, hit_set::HitSet, hitbox::HitboxSimple, map::Map, movement::MoveAlongRoad};
+
+/*
+    Zombies
+*/
+
+/// Something that walks the road backwards, from the exit to the start, and runs into bloons on the way (zombie bloons, unpopped army).
+/// Every bloon it runs into takes `damage`, and the damage actually done comes off the zombie's own hp.
+/// It's gone once its hp runs out, or when it gets to the start of the track.
+#[derive(Component, Clone, Serialize, Deserialize)]
+pub struct Zombie {
+    pub tier: BloonTier, // what it looks like and how fast it goes
+    pub hp: i32,
+    pub damage: i32,
+    pub hit_bloons: HitSet, // a bloon (and its children) is only run into once
+}
+
+/// Create a zombie of a bloon tier at the end of the given track. None if the map has no track.
+pub fn create_zombie(tier: BloonTier, damage: i32, map: &Map)->Option<(Zombie, MoveAlongRoad, HitboxSimple, Transform, Sprite)> {
+    let &end = map.path.last()?;
+    let last = map.path.len() - 1;
+    return Some((
+        Zombie { tier, hp: tier.get_base_hp(), damage, hit_bloons: HitSet::default() },
+        MoveAlongRoad { target_node: last, road_pos: map.cumulative_dist[last], waypoint: end, velocity: tier.get_base_speed(), reversed: true },
+        HitboxSimple { radius: tier.get_base_hitbox_radius() },
+        Transform::from_xyz(end.x, end.y, 1.),
+        get_zombie_sprite(tier),
+    ));
+}
+
+/*
+    Systems
+*/
+
+/// Zombies run into the bloons they overlap. Runs after the damage of the tick has been applied, so bloons that were just popped are left alone.
+/// Bloons are run into in a stable order (by id), so that the outcome doesn't depend on query order.
+pub fn zombie_hits(mut cmd: Commands, mut zombies: Query<(Entity, &mut Zombie, &HitboxSimple, &Transform)>, mut bloons: Query<(Entity, &mut Bloon, &HitboxSimple, &Transform)>) {
+    for (ze, mut zombie, zhb, zpos) in &mut zombies {
+        let mut hits: Vec<_> = bloons.iter().filter(|(_, bloon, bhb, bpos)| {
+            bloon.hp > 0 && !zombie.hit_bloons.has_hit(&bloon.bid) &&
+            hypot(zpos.translation.x - bpos.translation.x, zpos.translation.y - bpos.translation.y) < zhb.radius + bhb.radius
+        }).map(|(be, bloon, _, _)| (be, (bloon.bid.family, bloon.bid.layer, bloon.bid.tree))).collect();
+        hits.sort_unstable_by_key(|(_, id)| *id);
+        for (be, _) in hits {
+            let Ok((_, mut bloon, _, _)) = bloons.get_mut(be) else { continue; };
+            let dealt = zombie.damage.min(bloon.hp).min(zombie.hp);
+            bloon.hp -= dealt;
+            zombie.hp -= dealt;
+            zombie.hit_bloons.insert(&bloon.bid);
+            if zombie.hp <= 0 { cmd.entity(ze).despawn(); break; }
+        }
+    }
+}
+
+// TODO: remove from here
+pub fn get_zombie_sprite(tier: BloonTier)->Sprite {
+    let mut sprite = crate::core::bloon::get_bloon_sprite(tier);
+    sprite.color = sprite.color.mix(&Color::srgb(0.3, 0.6, 0.2), 0.6);
+    return sprite;
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use crate::core::{bloon::BloonID, game::GameState, movement::{advance_move_along_road, despawn_exited_road_entities}};
+
+    use super::*;
+
+    fn spawn_zombie(world: &mut World, hp: i32, damage: i32)->Entity {
+        let zombie = Zombie { tier: BloonTier::Red, hp, damage, hit_bloons: HitSet::default() };
+        return world.spawn((zombie, HitboxSimple { radius: 5. }, Transform::default())).id();
+    }
+
+    fn spawn_bloon(world: &mut World, family: u32, hp: i32)->Entity {
+        let mut bloon = Bloon::with(BloonTier::Red, 0);
+        bloon.bid = BloonID::new(family);
+        bloon.hp = hp;
+        return world.spawn((bloon, HitboxSimple { radius: 5. }, Transform::default())).id();
+    }
+
+    fn hp(world: &World, e: Entity)->i32 {
+        return world.get::<Bloon>(e).unwrap().hp;
+    }
+
+    #[test]
+    fn runs_into_bloons_until_out_of_hp() {
+        let mut world = World::new();
+        let zombie = spawn_zombie(&mut world, 3, 2);
+        // run into in id order, not spawn order
+        let bloons = [spawn_bloon(&mut world, 3, 5), spawn_bloon(&mut world, 1, 1), spawn_bloon(&mut world, 2, 5)];
+        world.run_system_once(zombie_hits).unwrap();
+        assert_eq!(bloons.map(|b| hp(&world, b)), [5, 0, 3]);
+        assert!(world.get_entity(zombie).is_err());
+    }
+
+    #[test]
+    fn runs_into_each_bloon_once() {
+        let mut world = World::new();
+        let zombie = spawn_zombie(&mut world, 100, 1);
+        let bloon = spawn_bloon(&mut world, 1, 5);
+        world.run_system_once(zombie_hits).unwrap();
+        world.run_system_once(zombie_hits).unwrap();
+        assert_eq!(hp(&world, bloon), 4);
+        assert_eq!(world.get::<Zombie>(zombie).unwrap().hp, 99);
+    }
+
+    #[test]
+    fn walks_the_track_backwards() {
+        let map = Map::get_map(1);
+        let (_, mut re, _, mut pos, _) = create_zombie(BloonTier::Red, 1, &map).unwrap();
+        let last = map.path.len() - 1;
+        let before = pos.translation.truncate().distance(map.path[last - 1]);
+        advance_move_along_road(10., &map, &mut re, &mut pos);
+        assert_eq!(re.road_pos, map.cumulative_dist[last] - 10.);
+        assert!(pos.translation.truncate().distance(map.path[last - 1]) < before - 9.);
+        advance_move_along_road(map.cumulative_dist[last], &map, &mut re, &mut pos);
+        assert_eq!(re.target_node, map.path.len());
+        assert_eq!(pos.translation.truncate(), map.path[0]);
+    }
+
+    #[test]
+    fn leaves_at_the_start_without_costing_lives() {
+        let mut world = World::new();
+        let map = Map::get_map(1);
+        let mut zombie = create_zombie(BloonTier::Red, 1, &map).unwrap();
+        zombie.1.target_node = map.path.len(); // what `advance_move_along_road` leaves once it gets to the start
+        let e = world.spawn(zombie).id();
+        world.insert_resource(map);
+        world.insert_resource(GameState::default());
+        world.run_system_once(despawn_exited_road_entities).unwrap();
+        assert!(world.get_entity(e).is_err());
+        assert_eq!(world.resource::<GameState>().lives, GameState::default().lives);
+    }
+
+    #[test]
+    fn no_zombie_without_a_track() {
+        assert!(create_zombie(BloonTier::Red, 1, &Map::default()).is_none());
+    }
+}
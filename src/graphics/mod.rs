@@ -11,14 +11,26 @@ impl Plugin for GraphicsPlugin {
         app.add_plugins(FrameTimeDiagnosticsPlugin)
         .add_systems(Startup, ui::init_camera)
         .add_systems(Startup, ui::init_text)
-        .add_systems(Update, ui::display_stats);
+        .add_systems(Update, (ui::display_stats, ui::draw_obstacles, ui::report_tower_upgrades, ui::report_tower_sales, ui::report_hero_levels));
 
         app.add_systems(Update, (
             user_input::keybind_spawn_bloon, 
             user_input::keybind_global_damage, 
             // user_input::keybind_spawn_projectile,
             user_input::keybind_spawn_projectile_number,
-        ));
+            user_input::keybind_spawn_high_pierce_projectiles,
+            user_input::keybind_place_tower,
+            user_input::keybind_cycle_targeting,
+            user_input::keybind_upgrade_tower,
+            user_input::keybind_sell_tower,
+            user_input::keybind_heli_follow,
+            user_input::keybind_use_ability,
+            user_input::keybind_start_round,
+            user_input::keybind_speed,
+            user_input::keybind_round_end_sweep,
+            user_input::keybind_save_replay,
+        ))
+        .add_systems(Update, user_input::keybind_save_load);
         app.add_systems(FixedUpdate, (
             // user_input::keybind_spawn_bloon, 
             // user_input::keybind_global_damage, 
@@ -1,6 +1,8 @@
 use bevy::prelude::*;
 use bevy::diagnostic::{DiagnosticsStore,FrameTimeDiagnosticsPlugin};
 
+use crate::core::{hero::HeroLevelUpEvent, map::Map, tower::{TowerSoldEvent, TowerUpgradedEvent}, upgradable::Upgradable};
+
 /*
     Camera
 */
@@ -9,6 +11,20 @@ pub fn init_camera(mut cmd: Commands) {
     cmd.spawn(Camera2d);
 }
 
+/*
+    Map
+*/
+
+/// Outline obstacles; the taller they are, the lighter
+pub fn draw_obstacles(map: Res<Map>, mut gizmos: Gizmos) {
+    for obstacle in &map.obstacles {
+        let shade = (0.3 + obstacle.height / 40.).min(1.);
+        let mut outline = obstacle.polygon.clone();
+        outline.extend(obstacle.polygon.first());
+        gizmos.linestrip_2d(outline, Color::srgb(shade, shade, shade));
+    }
+}
+
 /*
     FPS display
 */
@@ -68,3 +84,28 @@ pub fn init_text(mut cmd: Commands) {
         }
     ));
 }
+
+/*
+    Tower feedback
+*/
+
+/// No tower panel yet, so just say what happened
+pub fn report_tower_upgrades(mut upgraded_er: EventReader<TowerUpgradedEvent>) {
+    for ev in upgraded_er.read() {
+        if let Upgradable::Crosspath520(p1, p2, p3) = ev.upgrades {
+            info!("Tower {} upgraded to {}{}{}", ev.id.0, p1, p2, p3);
+        }
+    }
+}
+
+pub fn report_tower_sales(mut sold_er: EventReader<TowerSoldEvent>) {
+    for ev in sold_er.read() {
+        info!("Tower {} sold for {}", ev.id.0, ev.refund);
+    }
+}
+
+pub fn report_hero_levels(mut level_up_er: EventReader<HeroLevelUpEvent>) {
+    for ev in level_up_er.read() {
+        info!("Hero {} reached level {}", ev.id.0, ev.level);
+    }
+}
@@ -1,55 +1,48 @@
-use bevy::math::{vec2, vec3};
+use bevy::math::vec2;
 use bevy::prelude::*;
 use bevy::window::PrimaryWindow;
 
-use crate::core::bloon::BloonModifier;
-use crate::core::bloon::{create_bloon, BloonTier};
-use crate::core::event::GlobalDamageEvent;
-use crate::core::hitbox::HitboxSimple;
+use crate::core::ability::{Abilities, Ability, AbilityTargetCommand, AbilityTargeting};
+use crate::core::bloon::{Bloon, BloonTier};
+use crate::core::command::{PendingPlayerCommands, PlayerCommand};
+use crate::core::game::{GameState, RoundEndSweep, BASE_TICK_HZ};
 use crate::core::map::Map;
-use crate::core::movement::MoveSimple;
-use crate::core::projectile::{get_projectile_sprite, DamageDealer, LifetimeTick};
+use crate::core::replay::ReplayRecorder;
+use crate::core::save::{load_game, save_game, SaveGame};
+use crate::core::sim_rng::SimRng;
+use crate::core::subtower::Subtower;
+use crate::core::tower::{Tower, TowerId, TowerType};
 
-pub fn keybind_spawn_bloon(mut cmd: Commands, keyboard_input: Res<ButtonInput<KeyCode>>, map: Res<Map>) {
+/// Cursor position in world coordinates (the camera sits at the origin)
+fn cursor_world_pos(window: &Window)->Option<Vec2> {
+    return window.cursor_position().map(|pos| vec2(pos.x-window.width()/2., -(pos.y-window.height()/2.)));
+}
+
+pub fn keybind_spawn_bloon(keyboard_input: Res<ButtonInput<KeyCode>>, mut pending: ResMut<PendingPlayerCommands>) {
     if keyboard_input.just_pressed(KeyCode::KeyC) {
-        cmd.spawn(create_bloon(BloonTier::Ceramic, &*map));
+        pending.push(PlayerCommand::SpawnBloon { tier: BloonTier::Ceramic });
     } else if keyboard_input.just_pressed(KeyCode::KeyB) {
-        cmd.spawn(create_bloon(BloonTier::BAD, &*map));
+        pending.push(PlayerCommand::SpawnBloon { tier: BloonTier::BAD });
+    } else if keyboard_input.just_pressed(KeyCode::KeyZ) {
+        pending.push(PlayerCommand::SpawnZombie { tier: BloonTier::Ceramic, damage: 1 });
     }
 }
 
-pub fn keybind_global_damage(mut global_damage_ev: EventWriter<GlobalDamageEvent>, keyboard_input: Res<ButtonInput<KeyCode>>) {
+pub fn keybind_global_damage(keyboard_input: Res<ButtonInput<KeyCode>>, mut pending: ResMut<PendingPlayerCommands>) {
     if keyboard_input.just_pressed(KeyCode::KeyD) {
-        global_damage_ev.send(GlobalDamageEvent { damage: 20000, status_effect: None });
+        pending.push(PlayerCommand::GlobalDamage { damage: 20000 });
     }
 }
 
-pub fn keybind_spawn_projectile(mut cmd: Commands, keyboard_input: Res<ButtonInput<MouseButton>>, window: Single<&Window, With<PrimaryWindow>>) {
+pub fn keybind_spawn_projectile(keyboard_input: Res<ButtonInput<MouseButton>>, window: Single<&Window, With<PrimaryWindow>>, mut pending: ResMut<PendingPlayerCommands>) {
     if keyboard_input.pressed(MouseButton::Left) {
-        if let Some(pos) = window.cursor_position() {
-            let vx = (pos.x-window.width()/2.)/10.;
-            let vy = -(pos.y-window.height()/2.)/10.;
-            let damage = 1;
-            let pierce = 20;
-            for i in 0..100 {
-                cmd.spawn_batch(vec![
-                    simple_projectile(damage, pierce, vec2(vx + (i as f32/20.),vy + (i as f32/20.))),
-                    simple_projectile(damage, pierce, vec2(vx+5. + (i as f32/20.),vy + (i as f32/20.))),
-                    simple_projectile(damage, pierce, vec2(vx-5. + (i as f32/20.),vy + (i as f32/20.))),
-                    simple_projectile(damage, pierce, vec2(vx + (i as f32/20.),vy+5. + (i as f32/20.))),
-                    simple_projectile(damage, pierce, vec2(vx + (i as f32/20.),vy-5. + (i as f32/20.))),
-                ]);
-            }
+        if let Some(pos) = cursor_world_pos(&window) {
+            pending.push(PlayerCommand::SprayProjectiles { velocity: pos / 10., damage: 1, pierce: 20 });
         }
     }
 }
 
-pub fn keybind_spawn_projectile_number(mut cmd: Commands, keyboard_input: Res<ButtonInput<KeyCode>>, window: Single<&Window, With<PrimaryWindow>>) {
-    // if keyboard_input.just_pressed(KeyCode::KeyP) {
-    //     cmd.insert_resource(Time::<Fixed>::from_hz(256.0));
-    //     return;
-    // }
-
+pub fn keybind_spawn_projectile_number(keyboard_input: Res<ButtonInput<KeyCode>>, window: Single<&Window, With<PrimaryWindow>>, mut pending: ResMut<PendingPlayerCommands>) {
     let damage = if keyboard_input.just_pressed(KeyCode::Numpad1) {
         1
     } else if keyboard_input.just_pressed(KeyCode::Numpad2) {
@@ -71,21 +64,223 @@ pub fn keybind_spawn_projectile_number(mut cmd: Commands, keyboard_input: Res<Bu
     } else {
         return;
     };
-    if let Some(pos) = window.cursor_position() {
-        let vx = (pos.x-window.width()/2.)/10.;
-        let vy = -(pos.y-window.height()/2.)/10.;
-        let pierce = 10;
-        cmd.spawn(simple_projectile(damage, pierce, vec2(vx,vy)));
-    }
-}
-
-fn simple_projectile(damage: i32, pierce: u32, velocity: Vec2)->(MoveSimple, DamageDealer, Transform, HitboxSimple, LifetimeTick, Sprite) {
-    (
-        MoveSimple { velocity, bounce: 0, collide_height: None },
-        DamageDealer { damage: damage, pierce: pierce, cannot_pop_modifiers: (0 as BloonModifier), cannot_target_modifiers: (0 as BloonModifier), hit_bloons: vec![] },
-        Transform::from_translation(vec3(0.,0.,1.)),
-        HitboxSimple { radius: 5. },
-        LifetimeTick { lifetime: 40 },
-        get_projectile_sprite(),
-    )
-}
\ No newline at end of file
+    if let Some(pos) = cursor_world_pos(&window) {
+        pending.push(PlayerCommand::SpawnProjectile { velocity: pos / 10., damage, pierce: 10 });
+    }
+}
+
+/// Stress test for hit history: a ring of very high pierce projectiles (throw it at a few BADs)
+pub fn keybind_spawn_high_pierce_projectiles(keyboard_input: Res<ButtonInput<KeyCode>>, mut pending: ResMut<PendingPlayerCommands>) {
+    if keyboard_input.just_pressed(KeyCode::KeyH) {
+        for i in 0..200 {
+            let angle = i as f32 * std::f32::consts::TAU / 200.;
+            pending.push(PlayerCommand::SpawnProjectile { velocity: vec2(angle.cos(), angle.sin()) * 2., damage: 1, pierce: 5000 });
+        }
+    }
+}
+
+/// T dart monkey, Y tack shooter, U mortar, I alchemist, O village, J heli, K ace, L spike factory, M banana farm, N ice monkey, V glue gunner, Q quincy (hero)
+pub fn keybind_place_tower(keyboard_input: Res<ButtonInput<KeyCode>>, window: Single<&Window, With<PrimaryWindow>>, mut pending: ResMut<PendingPlayerCommands>) {
+    let tower_type = if keyboard_input.just_pressed(KeyCode::KeyT) {
+        TowerType::DartMonkey
+    } else if keyboard_input.just_pressed(KeyCode::KeyY) {
+        TowerType::TackShooter
+    } else if keyboard_input.just_pressed(KeyCode::KeyU) {
+        TowerType::MortarMonkey
+    } else if keyboard_input.just_pressed(KeyCode::KeyI) {
+        TowerType::AlchMonkey
+    } else if keyboard_input.just_pressed(KeyCode::KeyO) {
+        TowerType::Village
+    } else if keyboard_input.just_pressed(KeyCode::KeyJ) {
+        TowerType::HeliMonkey
+    } else if keyboard_input.just_pressed(KeyCode::KeyK) {
+        TowerType::AceMonkey
+    } else if keyboard_input.just_pressed(KeyCode::KeyL) {
+        TowerType::SpikeShooter
+    } else if keyboard_input.just_pressed(KeyCode::KeyM) {
+        TowerType::BananaFarm
+    } else if keyboard_input.just_pressed(KeyCode::KeyN) {
+        TowerType::IceMonkey
+    } else if keyboard_input.just_pressed(KeyCode::KeyV) {
+        TowerType::GlueMonkey
+    } else if keyboard_input.just_pressed(KeyCode::KeyQ) {
+        TowerType::Quincy
+    } else {
+        return;
+    };
+    if let Some(pos) = cursor_world_pos(&window) {
+        pending.push(PlayerCommand::PlaceTower { tower_type, pos });
+    }
+}
+
+/// Towers the player can select (not subtowers)
+type ClickableTowers<'w, 's> = Query<'w, 's, (&'static TowerId, &'static Tower, &'static Transform), Without<Subtower>>;
+
+/// The tower closest to the cursor (there's no tower selection yet)
+fn tower_near_cursor<'a>(window: &Window, towers: &'a ClickableTowers)->Option<(TowerId, &'a Tower)> {
+    let pos = cursor_world_pos(window)?;
+    return towers.iter().min_by(|(_, _, a), (_, _, b)| {
+        a.translation.truncate().distance(pos).total_cmp(&b.translation.truncate().distance(pos))
+    }).map(|(id, tower, _)| (*id, tower));
+}
+
+/// Cycle the targeting mode of the tower closest to the cursor
+pub fn keybind_cycle_targeting(keyboard_input: Res<ButtonInput<KeyCode>>, window: Single<&Window, With<PrimaryWindow>>, towers: ClickableTowers, mut pending: ResMut<PendingPlayerCommands>) {
+    if keyboard_input.just_pressed(KeyCode::Tab) {
+        if let Some((id, tower)) = tower_near_cursor(&window, &towers) {
+            pending.push(PlayerCommand::SetTargeting { tower: id, mode: (tower.cur_targeting_mode() + 1) % tower.targeting_mode_count() });
+        }
+    }
+}
+
+/// Upgrade the tower closest to the cursor: `,` `.` `/` for paths 1, 2, 3
+pub fn keybind_upgrade_tower(keyboard_input: Res<ButtonInput<KeyCode>>, window: Single<&Window, With<PrimaryWindow>>, towers: ClickableTowers, mut pending: ResMut<PendingPlayerCommands>) {
+    let path = if keyboard_input.just_pressed(KeyCode::Comma) {
+        1
+    } else if keyboard_input.just_pressed(KeyCode::Period) {
+        2
+    } else if keyboard_input.just_pressed(KeyCode::Slash) {
+        3
+    } else {
+        return;
+    };
+    if let Some((id, _)) = tower_near_cursor(&window, &towers) {
+        pending.push(PlayerCommand::UpgradeTower { tower: id, path });
+    }
+}
+
+/// Sell the tower closest to the cursor
+pub fn keybind_sell_tower(keyboard_input: Res<ButtonInput<KeyCode>>, window: Single<&Window, With<PrimaryWindow>>, towers: ClickableTowers, mut pending: ResMut<PendingPlayerCommands>) {
+    if keyboard_input.just_pressed(KeyCode::Backspace) {
+        if let Some((id, _)) = tower_near_cursor(&window, &towers) {
+            pending.push(PlayerCommand::SellTower { tower: id });
+        }
+    }
+}
+
+/// G makes the heli of the tower closest to the cursor follow the cursor; G again locks it in place
+pub fn keybind_heli_follow(keyboard_input: Res<ButtonInput<KeyCode>>, window: Single<&Window, With<PrimaryWindow>>, towers: ClickableTowers, mut following: Local<Option<(TowerId, Vec2)>>, mut pending: ResMut<PendingPlayerCommands>) {
+    if keyboard_input.just_pressed(KeyCode::KeyG) {
+        if let Some((id, _)) = following.take() {
+            pending.push(PlayerCommand::SetSubtowerTarget { tower: id, target: None });
+        } else if let Some((id, _)) = tower_near_cursor(&window, &towers) {
+            *following = Some((id, Vec2::NAN));
+        }
+    }
+    // only send the cursor position when it moved, so that replays don't fill up with the same target every frame
+    let Some((id, last)) = following.as_mut() else { return; };
+    let Some(pos) = cursor_world_pos(&window) else { return; };
+    if last.is_nan() || last.distance(pos) > 2. {
+        *last = pos;
+        pending.push(PlayerCommand::SetSubtowerTarget { tower: *id, target: Some(pos) });
+    }
+}
+
+const ABILITY_KEYS: [KeyCode; 9] = [
+    KeyCode::Digit1, KeyCode::Digit2, KeyCode::Digit3, KeyCode::Digit4, KeyCode::Digit5,
+    KeyCode::Digit6, KeyCode::Digit7, KeyCode::Digit8, KeyCode::Digit9,
+];
+
+/// 1-9 use the abilities of all towers, in the order the towers were placed. Abilities that need a target are aimed at the cursor
+/// and fired with the right mouse button (the left one is the debug spray); Escape cancels.
+pub fn keybind_use_ability(
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mouse_input: Res<ButtonInput<MouseButton>>,
+    window: Single<&Window, With<PrimaryWindow>>,
+    abilities: Query<(&TowerId, &Abilities), Without<Subtower>>,
+    towers: ClickableTowers,
+    bloons: Query<(&Bloon, &Transform)>,
+    mut aiming: Local<Option<(TowerId, String, AbilityTargeting)>>,
+    mut pending: ResMut<PendingPlayerCommands>,
+) {
+    if let Some(slot) = ABILITY_KEYS.iter().position(|k| keyboard_input.just_pressed(*k)) {
+        let mut all: Vec<(TowerId, usize, &Ability)> = abilities.iter()
+            .flat_map(|(id, a)| a.abilities.iter().enumerate().map(|(i, ability)| (*id, i, ability)))
+            .collect();
+        all.sort_by_key(|(id, i, _)| (*id, *i));
+        *aiming = None;
+        let Some((tower, _, a)) = all.get(slot) else { return; };
+        let (tower, ability, targeting) = (*tower, a.def.name.clone(), a.def.targeting);
+        if targeting == AbilityTargeting::None {
+            pending.push(PlayerCommand::UseAbility { tower, ability, target: AbilityTargetCommand::None });
+        } else {
+            *aiming = Some((tower, ability, targeting));
+        }
+        return;
+    }
+    if keyboard_input.just_pressed(KeyCode::Escape) {
+        *aiming = None;
+        return;
+    }
+    let Some((tower, ability, targeting)) = aiming.clone() else { return; };
+    if !mouse_input.just_pressed(MouseButton::Right) { return; }
+    let Some(pos) = cursor_world_pos(&window) else { return; };
+    let target = match targeting {
+        AbilityTargeting::None => AbilityTargetCommand::None,
+        AbilityTargeting::Road => AbilityTargetCommand::Road(pos),
+        AbilityTargeting::Bloon => {
+            let closest = bloons.iter().min_by(|(_, a), (_, b)| {
+                a.translation.truncate().distance(pos).total_cmp(&b.translation.truncate().distance(pos))
+            });
+            let Some((bloon, _)) = closest else { return; };
+            AbilityTargetCommand::Bloon(bloon.bid.clone())
+        },
+        AbilityTargeting::Tower => {
+            let Some((id, _)) = tower_near_cursor(&window, &towers) else { return; };
+            AbilityTargetCommand::Tower(id)
+        },
+    };
+    pending.push(PlayerCommand::UseAbility { tower, ability, target });
+    *aiming = None;
+}
+
+pub fn keybind_start_round(keyboard_input: Res<ButtonInput<KeyCode>>, mut pending: ResMut<PendingPlayerCommands>) {
+    if keyboard_input.just_pressed(KeyCode::Space) {
+        pending.push(PlayerCommand::StartRound);
+    }
+}
+
+/// Toggle fast forward
+pub fn keybind_speed(keyboard_input: Res<ButtonInput<KeyCode>>, time: Res<Time<Fixed>>, mut pending: ResMut<PendingPlayerCommands>) {
+    if keyboard_input.just_pressed(KeyCode::KeyP) {
+        let hz = if time.timestep().as_secs_f64() < 1. / BASE_TICK_HZ - 1e-9 { BASE_TICK_HZ } else { BASE_TICK_HZ * 4. };
+        pending.push(PlayerCommand::SetSpeed { hz });
+    }
+}
+
+/// Toggle the round end sweep between the default and off
+pub fn keybind_round_end_sweep(keyboard_input: Res<ButtonInput<KeyCode>>, game: Res<GameState>, mut pending: ResMut<PendingPlayerCommands>) {
+    if keyboard_input.just_pressed(KeyCode::KeyR) {
+        let sweep = if game.round_end_sweep.is_off() { RoundEndSweep::default() } else { RoundEndSweep::off() };
+        pending.push(PlayerCommand::SetRoundEndSweep { sweep });
+    }
+}
+
+pub fn keybind_save_replay(keyboard_input: Res<ButtonInput<KeyCode>>, recorder: Res<ReplayRecorder>, rng: Res<SimRng>, map: Res<Map>) {
+    if keyboard_input.just_pressed(KeyCode::F5) {
+        if recorder.from_save {
+            error!("Could not save replay: the game was loaded from a save");
+            return;
+        }
+        match recorder.to_replay(&*rng, &*map).save("replay.ron") {
+            Ok(()) => info!("Saved replay to replay.ron"),
+            Err(e) => error!("Could not save replay: {e}"),
+        }
+    }
+}
+
+/// F6 saves the game, F9 loads it back
+pub fn keybind_save_load(world: &mut World) {
+    let keyboard_input = world.resource::<ButtonInput<KeyCode>>();
+    if keyboard_input.just_pressed(KeyCode::F6) {
+        match save_game(world).save("savegame.ron") {
+            Ok(()) => info!("Saved game to savegame.ron"),
+            Err(e) => error!("Could not save game: {e}"),
+        }
+    } else if keyboard_input.just_pressed(KeyCode::F9) {
+        match SaveGame::load("savegame.ron") {
+            Ok(save) => load_game(world, save),
+            Err(e) => error!("Could not load game: {e}"),
+        }
+    }
+}
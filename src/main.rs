@@ -7,7 +7,40 @@ mod core;
 use core::*;
 
 fn main() {
+    // `--replay <file>` plays a recorded game back headlessly and checks it for desyncs
+    // `--hash-log <file>` additionally writes the state hash of every tick, for diffing two runs
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(i) = args.iter().position(|a| a == "--replay") {
+        let Some(path) = args.get(i + 1) else {
+            eprintln!("Usage: --replay <file>");
+            std::process::exit(2);
+        };
+        let replay = match replay::Replay::load(path) {
+            Ok(replay) => replay,
+            Err(e) => {
+                eprintln!("Could not load replay {path}: {e}");
+                std::process::exit(2);
+            },
+        };
+        let hash_log_path = args.iter().position(|a| a == "--hash-log").and_then(|i| args.get(i + 1));
+        let result = replay::run_replay(replay, hash_log_path.is_some());
+        if let (Some(path), Some(log)) = (hash_log_path, result.hash_log) {
+            let lines: Vec<String> = log.iter().map(|(tick, hash)| format!("{tick} {hash:016x}")).collect();
+            if let Err(e) = std::fs::write(path, lines.join("\n")) {
+                eprintln!("Could not write hash log {path}: {e}");
+            }
+        }
+        match result.desync {
+            None => println!("Replay verified"),
+            Some(tick) => {
+                println!("Replay desynced at tick {tick}");
+                std::process::exit(1);
+            },
+        }
+        return;
+    }
+
     App::new()
         .add_plugins((DefaultPlugins, GraphicsPlugin, BTD0CorePlugin))
         .run();
-}
\ No newline at end of file
+}